@@ -0,0 +1,92 @@
+// adaptive sampling: pixels stop getting samples once they have converged
+
+use crate::v3color::*;
+
+pub struct AdaptiveSettings {
+    /// the relative error under which a pixel is done
+    pub threshold: f32,
+    /// taken before looking at the error at all, so that
+    /// rare bright paths get a chance to show up
+    pub min_samples: i32
+}
+
+/// brightness below this gets the same absolute error as this,
+/// otherwise nearly black pixels would hardly ever converge
+const DARK_LUMINANCE: f32 = 0.1;
+
+/// running mean and variance of the brightness of a pixel's samples
+/// (Welford's algorithm), next to the sum of the colors
+pub struct PixelStats {
+    pub count: i32,
+    sum: V3,
+    mean: f32,
+    squared_deviations: f32
+}
+
+impl PixelStats {
+    pub fn new() -> PixelStats {
+        PixelStats { count: 0, sum: V3 { x: 0.0, y: 0.0, z: 0.0 }, mean: 0.0, squared_deviations: 0.0 }
+    }
+
+    pub fn add(&mut self, c: &Color) {
+        self.count += 1;
+        self.sum = self.sum + c.to_v3();
+        let l = luminance(c);
+        let delta = l - self.mean;
+        self.mean += delta / self.count as f32;
+        self.squared_deviations += delta * (l - self.mean);
+    }
+
+    pub fn color(&self) -> Color {
+        (self.sum / self.count as f32).to_color()
+    }
+
+    /// the standard error of the mean brightness, relative to it
+    pub fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+        let variance = self.squared_deviations / (self.count - 1) as f32;
+        (variance / self.count as f32).sqrt() / self.mean.max(DARK_LUMINANCE)
+    }
+
+    pub fn converged(&self, settings: &AdaptiveSettings) -> bool {
+        self.count >= settings.min_samples && self.relative_error() < settings.threshold
+    }
+}
+
+/// blue for the fewest samples, through green, to red for the most
+pub fn heatmap_color(samples: i32, min_samples: i32, max_samples: i32) -> Color {
+    let t = if max_samples > min_samples {
+        (samples - min_samples) as f32 / (max_samples - min_samples) as f32
+    } else {
+        1.0
+    };
+    Color {
+        r: (2.0*t - 1.0).max(0.0),
+        g: 1.0 - (2.0*t - 1.0).abs(),
+        b: (1.0 - 2.0*t).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_convergence() {
+        let settings = AdaptiveSettings { threshold: 0.01, min_samples: 4 };
+        let mut flat = PixelStats::new();
+        let mut noisy = PixelStats::new();
+        for i in 0..8 {
+            flat.add(&Color { r: 0.5, g: 0.5, b: 0.5 });
+            let v = if i % 2 == 0 { 0.0 } else { 1.0 };
+            noisy.add(&Color { r: v, g: v, b: v });
+            assert_eq!(i >= 3, flat.converged(&settings));
+            assert!(!noisy.converged(&settings));
+        }
+        assert!((noisy.color().r - 0.5).abs() < 1e-6);
+        // the sample variance of four zeroes and four ones is 2/7
+        assert!((noisy.relative_error() - (2.0/7.0f32/8.0).sqrt() / 0.5).abs() < 1e-5);
+    }
+}
@@ -1,16 +1,39 @@
 // Bounding Volume Hierarchy
 
-use crate::{v3color::*, shapes::*};
+use crate::{v3color::*, shapes::*, rng::*};
 
-use rand::{prelude as random, Rng};
+use rand::Rng;
 
 // aabb == Axis-Aligned Bounding Box
+#[derive(Copy, Clone, Debug)]
 pub struct Aabb {
     pub min: V3,
     pub max: V3
 }
 
 impl Aabb {
+    pub fn from_points(points: &[V3]) -> Aabb {
+        let first = Aabb { min: points[0], max: points[0] };
+        points[1..].iter().fold(first, |bbox, p| bbox.union(&Aabb { min: *p, max: *p }))
+    }
+
+    /// grow the box in the dimensions where it's thinner than `min_extent`,
+    /// so that flat boxes still get hit.
+    pub fn pad(&self, min_extent: f32) -> Aabb {
+        let pad_dimension = |min: f32, max: f32| if max - min < min_extent {
+            (min - min_extent/2.0, max + min_extent/2.0)
+        } else {
+            (min, max)
+        };
+        let (min_x, max_x) = pad_dimension(self.min.x, self.max.x);
+        let (min_y, max_y) = pad_dimension(self.min.y, self.max.y);
+        let (min_z, max_z) = pad_dimension(self.min.z, self.max.z);
+        Aabb {
+            min: V3 { x: min_x, y: min_y, z: min_z },
+            max: V3 { x: max_x, y: max_y, z: max_z }
+        }
+    }
+
     pub fn union(&self, other: &Aabb) -> Aabb {
         Aabb {
             min: V3 {
@@ -26,115 +49,345 @@ impl Aabb {
         }
     }
 
-    fn check_dimension(&self, ray: &Ray, tmin: &mut f32, tmax: &mut f32,
-                       getter:&Fn(&V3)->f32) -> bool {
-        let inv_d = 1.0 / getter(&ray.direction);
-        let mut t0 = (getter(&self.min) - getter(&ray.origin)) * inv_d;
-        let mut t1 = (getter(&self.max) - getter(&ray.origin)) * inv_d;
-        if inv_d < 0.0 {
-            std::mem::swap(&mut t0, &mut t1);
-        };
-        if t0 > *tmin {
-            *tmin = t0;
-        }
-        if t1 < *tmax {
-            *tmax = t1;
-        }
-        tmax > tmin
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x*d.y + d.y*d.z + d.z*d.x)
     }
 
-    pub fn hit(&self, ray: &Ray, t_range: &std::ops::Range<f32>) -> bool {
-        let mut tmin = t_range.start;
-        let mut tmax = t_range.end;
+    pub fn centroid(&self) -> V3 {
+        0.5 * (self.min + self.max)
+    }
 
-        if !self.check_dimension(ray, &mut tmin, &mut tmax, &V3::get_x) {
-            return false;
-        }
-        if !self.check_dimension(ray, &mut tmin, &mut tmax, &V3::get_y) {
-            return false;
-        }
-        if !self.check_dimension(ray, &mut tmin, &mut tmax, &V3::get_z) {
-            return false;
+    /// takes the inverse of the ray direction, computed once
+    /// for all the boxes the ray gets tested against
+    pub fn hit(&self, origin: &V3, inv_direction: &V3, t_start: f32, t_end: f32) -> bool {
+        let (mut tmin, mut tmax) = (t_start, t_end);
+        for &getter in &[V3::get_x, V3::get_y, V3::get_z] {
+            let inv_d = getter(inv_direction);
+            let mut t0 = (getter(&self.min) - getter(origin)) * inv_d;
+            let mut t1 = (getter(&self.max) - getter(origin)) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            tmin = if t0 > tmin { t0 } else { tmin };
+            tmax = if t1 < tmax { t1 } else { tmax };
+            if tmax <= tmin {
+                return false;
+            }
         }
         true
     }
 }
 
-pub struct BvhNode {
-    pub left: Box<Shape>,
-    pub right: Box<Shape>,
-    pub bbox: Aabb
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum BvhBuilder {
+    #[default]
+    /// binned surface area heuristic: splits where the
+    /// expected cost of hitting the children is the lowest
+    Sah,
+    /// sorts on a random axis and splits in the middle
+    Median
+}
+
+impl BvhBuilder {
+    pub fn from_name(name: &str) -> Option<BvhBuilder> {
+        match name {
+            "sah" => Some(BvhBuilder::Sah),
+            "median" => Some(BvhBuilder::Median),
+            _ => None
+        }
+    }
 }
 
-impl Shape for BvhNode {
+const SAH_BINS: usize = 12;
+/// SAH leaves hold at most that many shapes
+const MAX_LEAF_SIZE: usize = 4;
+/// the cost of going through a node, relative to hitting a shape
+const TRAVERSAL_COST: f32 = 0.125;
+/// the room on the traversal stack, which also bounds the depth of the tree
+const STACK_SIZE: usize = 64;
+/// past that depth the SAH builder splits in the middle, so that
+/// the rest of the tree can't get deeper than the stack allows
+const SAH_MAX_DEPTH: usize = 32;
+
+/// a shape for the builders, with its box computed once
+struct BuildItem {
+    shape: Box<dyn Shape>,
+    bbox: Aabb,
+    centroid: V3
+}
+
+/// interior nodes have their first child right after them and the
+/// second one at `offset`, leaves have `count` shapes from `offset`
+#[derive(Copy, Clone, Debug)]
+struct LinearNode {
+    bbox: Aabb,
+    offset: u32,
+    /// 0 for interior nodes
+    count: u16,
+    /// the axis the children were split along
+    axis: u8
+}
+
+const _: () = assert!(std::mem::size_of::<LinearNode>() == 32);
+
+/// a bounding volume hierarchy flattened depth first into an array
+pub struct Bvh {
+    nodes: Vec<LinearNode>,
+    /// ordered so that each leaf has its shapes next to each other
+    shapes: Vec<Box<dyn Shape>>,
+    /// the shapes without a bounding box, tested against every ray
+    unbounded: Vec<Box<dyn Shape>>
+}
+
+impl Shape for Bvh {
     fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
-        if !self.bbox.hit(&ray, t_range) {
-            return None;
+        let mut closest = None;
+        let mut t_end = t_range.end;
+        // first, so that a close hit on a ground plane culls the tree
+        for shape in &self.unbounded {
+            if let Some(hit) = shape.hit(ray, &(t_range.start..t_end)) {
+                t_end = hit.t;
+                closest = Some(hit);
+            }
         }
-        let hit_left = self.left.hit(&ray, t_range);
-        let hit_right = self.right.hit(&ray, t_range);
-        match (hit_left, hit_right) {
-            (Some(l), Some(r)) if l.t < r.t => Some(l),
-            (Some(_), Some(r)) => Some(r),
-            (Some(l), _) => Some(l),
-            (_, Some(r)) => Some(r),
-            _ => None
+        if self.nodes.is_empty() {
+            return closest;
         }
+        let inv_direction = ray.inverse_direction();
+        let direction_is_negative = [inv_direction.x < 0.0, inv_direction.y < 0.0, inv_direction.z < 0.0];
+        let mut stack = [0u32; STACK_SIZE];
+        let mut stack_len = 0;
+        let mut index = 0;
+        loop {
+            let node = &self.nodes[index];
+            if node.bbox.hit(&ray.origin, &inv_direction, t_range.start, t_end) {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    for shape in &self.shapes[first..first + node.count as usize] {
+                        if let Some(hit) = shape.hit(ray, &(t_range.start..t_end)) {
+                            t_end = hit.t;
+                            closest = Some(hit);
+                        }
+                    }
+                } else {
+                    // the near child first: once it has a hit, the
+                    // far one often gets culled by the shorter range
+                    let (near, far) = if direction_is_negative[node.axis as usize] {
+                        (node.offset as usize, index + 1)
+                    } else {
+                        (index + 1, node.offset as usize)
+                    };
+                    stack[stack_len] = far as u32;
+                    stack_len += 1;
+                    index = near;
+                    continue;
+                }
+            }
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            index = stack[stack_len] as usize;
+        }
+        closest
     }
 
-    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Aabb {
-        Aabb { min: self.bbox.min, max: self.bbox.max }
+    /// None when there's an unbounded shape, or nothing at all
+    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        if !self.unbounded.is_empty() {
+            return None;
+        }
+        self.nodes.first().map(|node| node.bbox)
     }
 }
 
-impl BvhNode {
-    pub fn compute_shapes_bvh(mut shapes: Vec<Box<Shape>>, t_range: &std::ops::Range<f32>) -> Box<Shape> {
-        let getter = match random::thread_rng().gen_range(0,3) {
-            0 => V3::get_x,
-            1 => V3::get_y,
-            _ => V3::get_z
+impl Bvh {
+    pub fn build(shapes: Vec<Box<dyn Shape>>, t_range: &std::ops::Range<f32>, builder: BvhBuilder) -> Bvh {
+        let mut items = Vec::with_capacity(shapes.len());
+        let mut unbounded = Vec::new();
+        for shape in shapes {
+            match shape.bounding_box(t_range) {
+                Some(bbox) => items.push(BuildItem { shape, bbox, centroid: bbox.centroid() }),
+                None => unbounded.push(shape)
+            }
+        }
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * items.len()),
+            shapes: Vec::with_capacity(items.len()),
+            unbounded
         };
-        shapes.sort_by(|a, b|
-            f32_cmp(
-                getter(&a.bounding_box(t_range).min),
-                getter(&b.bounding_box(t_range).min)));
-        match shapes.len() {
-            1 => shapes.pop().unwrap(),
-            2 => {
-                let snd = shapes.pop().unwrap();
-                let fst = shapes.pop().unwrap();
-                BvhNode::build_bhv(fst, snd, t_range)
-            },
-            _ => {
-                let r = shapes.split_off(shapes.len()/2); // modifies 'shapes'!!!
-                BvhNode::build_bhv(BvhNode::compute_shapes_bvh(shapes, t_range),
-                    BvhNode::compute_shapes_bvh(r, t_range), t_range)
+        if items.is_empty() {
+            return bvh;
+        }
+        match builder {
+            BvhBuilder::Sah => bvh.sah_split(items, 0),
+            // a fixed seed, so that the same scene always gets the same tree
+            BvhBuilder::Median => bvh.median_split(items, &mut Pcg32::new(0, 0))
+        }
+        bvh
+    }
+
+    fn push_leaf(&mut self, items: Vec<BuildItem>, bbox: Aabb) {
+        self.nodes.push(LinearNode { bbox, offset: self.shapes.len() as u32, count: items.len() as u16, axis: 0 });
+        self.shapes.extend(items.into_iter().map(|item| item.shape));
+    }
+
+    /// the second child's offset is only known once the
+    /// first subtree is in, see `start_second_child`
+    fn push_interior(&mut self, bbox: Aabb, axis: u8) -> usize {
+        self.nodes.push(LinearNode { bbox, offset: 0, count: 0, axis });
+        self.nodes.len() - 1
+    }
+
+    fn start_second_child(&mut self, index: usize) {
+        self.nodes[index].offset = self.nodes.len() as u32;
+    }
+
+    fn sah_split(&mut self, mut items: Vec<BuildItem>, depth: usize) {
+        let bbox = items[1..].iter().fold(items[0].bbox, |b, item| b.union(&item.bbox));
+        if items.len() == 1 {
+            return self.push_leaf(items, bbox);
+        }
+        if depth >= SAH_MAX_DEPTH {
+            return self.median_split(items, &mut Pcg32::new(0, 0));
+        }
+
+        // split along the axis where the centroids are the most spread
+        let centroids = Aabb::from_points(&items.iter().map(|i| i.centroid).collect::<Vec<_>>());
+        let extent = centroids.max - centroids.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        let getter = [V3::get_x, V3::get_y, V3::get_z][axis as usize];
+        let (axis_min, axis_extent) = (getter(&centroids.min), getter(&extent));
+        if axis_extent <= 0.0 {
+            // all the centroids at the same place: no split helps
+            if items.len() <= MAX_LEAF_SIZE {
+                return self.push_leaf(items, bbox);
             }
+            let right = items.split_off(items.len()/2);
+            let index = self.push_interior(bbox, axis);
+            self.sah_split(items, depth + 1);
+            self.start_second_child(index);
+            return self.sah_split(right, depth + 1);
+        }
+        let bin_of = |item: &BuildItem| usize::min(
+            ((getter(&item.centroid) - axis_min) / axis_extent * SAH_BINS as f32) as usize,
+            SAH_BINS - 1);
+
+        let mut bins: [(usize, Option<Aabb>); SAH_BINS] = [(0, None); SAH_BINS];
+        for item in &items {
+            let bin = &mut bins[bin_of(item)];
+            bin.0 += 1;
+            bin.1 = Some(bin.1.map_or(item.bbox, |b| b.union(&item.bbox)));
+        }
+        // the cost of splitting after each bin, the left side
+        // swept forwards then the right side backwards
+        let mut costs = [0.0; SAH_BINS - 1];
+        let (mut count, mut area_box): (usize, Option<Aabb>) = (0, None);
+        for (i, cost) in costs.iter_mut().enumerate() {
+            count += bins[i].0;
+            area_box = merge(area_box, bins[i].1);
+            *cost = count as f32 * area_box.map_or(0.0, |b| b.surface_area());
+        }
+        let (mut count, mut area_box): (usize, Option<Aabb>) = (0, None);
+        for i in (1..SAH_BINS).rev() {
+            count += bins[i].0;
+            area_box = merge(area_box, bins[i].1);
+            costs[i - 1] += count as f32 * area_box.map_or(0.0, |b| b.surface_area());
+        }
+        let (best_bin, best_cost) = costs.iter().enumerate()
+            .min_by(|a, b| f32_cmp(*a.1, *b.1)).unwrap();
+        let split_cost = TRAVERSAL_COST + best_cost / bbox.surface_area();
+        if items.len() <= MAX_LEAF_SIZE && items.len() as f32 <= split_cost {
+            return self.push_leaf(items, bbox);
         }
+        let (left, right): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| bin_of(item) <= best_bin);
+        let index = self.push_interior(bbox, axis);
+        self.sah_split(left, depth + 1);
+        self.start_second_child(index);
+        self.sah_split(right, depth + 1);
     }
 
-    fn build_bhv(left: Box<Shape>, right: Box<Shape>, 
-            t_range: &std::ops::Range<f32>) -> Box<BvhNode> {
-        let bbox = left.bounding_box(t_range)
-            .union(&right.bounding_box(t_range));
-        Box::new(BvhNode { left, right, bbox })
+    /// sorts on a random axis and splits in the middle, down to single shapes
+    fn median_split(&mut self, mut items: Vec<BuildItem>, rng: &mut Pcg32) {
+        let bbox = items[1..].iter().fold(items[0].bbox, |b, item| b.union(&item.bbox));
+        if items.len() == 1 {
+            return self.push_leaf(items, bbox);
+        }
+        let axis: u8 = rng.gen_range(0, 3);
+        let getter = [V3::get_x, V3::get_y, V3::get_z][axis as usize];
+        items.sort_by(|a, b| f32_cmp(getter(&a.bbox.min), getter(&b.bbox.min)));
+        let right = items.split_off(items.len()/2);
+        let index = self.push_interior(bbox, axis);
+        self.median_split(items, rng);
+        self.start_second_child(index);
+        self.median_split(right, rng);
+    }
+}
+
+fn merge(a: Option<Aabb>, b: Option<Aabb>) -> Option<Aabb> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.union(&b)),
+        (a, None) => a,
+        (None, b) => b
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{material::*, texture::*};
 
     #[test]
     fn test_aabb_hit() {
-        let range = 0.001..std::f32::MAX;
-        assert!(Aabb {
-            min: V3 {x: 1.0, y: 1.0, z: 1.0},
-            max: V3 {x: 2.0, y: 2.0, z: 2.0}
-        }.hit(&Ray {
+        let ray = Ray {
             origin: V3 {x: 0.0, y: 0.0, z: 0.0},
             direction: V3 {x: 1.0, y: 1.0, z: 1.0},
             time: 0.0
-        }, &range))
+        };
+        assert!(Aabb {
+            min: V3 {x: 1.0, y: 1.0, z: 1.0},
+            max: V3 {x: 2.0, y: 2.0, z: 2.0}
+        }.hit(&ray.origin, &ray.inverse_direction(), 0.001, f32::MAX))
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_bvh_matches_brute_force() {
+        let mut rng = Pcg32::new(1, 0);
+        let mut random_v3 = |scale: f32| V3 {
+            x: scale * (rng.gen::<f32>() - 0.5),
+            y: scale * (rng.gen::<f32>() - 0.5),
+            z: scale * (rng.gen::<f32>() - 0.5)
+        };
+        let spheres: Vec<(V3, f32)> = (0..200).map(|_| (random_v3(20.0), 0.2 + random_v3(1.0).x.abs())).collect();
+        let rays: Vec<Ray> = (0..500).map(|_| Ray { origin: random_v3(30.0), direction: random_v3(1.0), time: 0.0 }).collect();
+        let make_shapes = || spheres.iter().map(|&(center, radius)| Box::new(Sphere {
+            center, radius,
+            material: Box::new(Lambertian { albedo: Box::new(ConstantTexture { color: Color { r: 0.5, g: 0.5, b: 0.5 } }) })
+        }) as Box<dyn Shape>).collect::<Vec<_>>();
+        let make_shapes = || {
+            let mut shapes = make_shapes();
+            shapes.push(Box::new(InfinitePlane {
+                point: V3 {x: 0.0, y: -5.0, z: 0.0},
+                normal: V3 {x: 0.0, y: 1.0, z: 0.0},
+                material: Box::new(Lambertian { albedo: Box::new(ConstantTexture { color: Color { r: 0.5, g: 0.5, b: 0.5 } }) })
+            }));
+            shapes
+        };
+        let shapes = make_shapes();
+        for &builder in &[BvhBuilder::Sah, BvhBuilder::Median] {
+            let bvh = Bvh::build(make_shapes(), &(0.0..1.0), builder);
+            for ray in &rays {
+                let expected = shapes.iter().filter_map(|s| s.hit(ray, &(0.001..f32::MAX)))
+                    .map(|hit| hit.t).min_by(|a, b| f32_cmp(*a, *b));
+                assert_eq!(expected, bvh.hit(ray, &(0.001..f32::MAX)).map(|hit| hit.t), "{:?}", builder);
+            }
+        }
+    }
+}
@@ -1,7 +1,6 @@
-use crate::{v3color::*, shapes::*};
+use crate::{v3color::*, shapes::*, sampler::*};
 
 use std::f32::consts::PI;
-use rand::{prelude as random, Rng};
 
 pub struct Camera {
     origin: V3,
@@ -15,18 +14,20 @@ pub struct Camera {
     time2: f32
 }
 
-fn random_in_unit_disk() -> V3 {
-    let mut rng = random::thread_rng();
-    let mut p;
-    let unit = V3 { x: 1.0, y: 1.0, z: 0.0};
-    loop {
-        p = 2.0 * V3 { 
-            x: rng.gen::<f32>(),
-            y: rng.gen::<f32>(),
-            z: 0.0
-        } - unit;
-        if p.squared_length() < 1.0 { break p; }
+/// Shirley's concentric mapping of the square to the disk,
+/// which keeps the stratification of the samples
+fn sample_unit_disk(sampler: &mut dyn Sampler) -> V3 {
+    let (u, v) = sampler.get_2d();
+    let (a, b) = (2.0*u - 1.0, 2.0*v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return V3 { x: 0.0, y: 0.0, z: 0.0 };
     }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI/4.0 * (b/a))
+    } else {
+        (b, PI/2.0 - PI/4.0 * (a/b))
+    };
+    V3 { x: r*theta.cos(), y: r*theta.sin(), z: 0.0 }
 }
 
 pub struct CameraParams<'a> {
@@ -61,10 +62,10 @@ impl Camera {
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius*random_in_unit_disk();
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = self.lens_radius*sample_unit_disk(sampler);
         let offset = self.u*rd.x + self.v*rd.y;
-        let time: f32 = self.time1 + rand::thread_rng().gen::<f32>()*(self.time2-self.time1);
+        let time: f32 = self.time1 + sampler.get_1d()*(self.time2-self.time1);
         Ray {
             origin: self.origin + offset,
             direction: self.lower_left_corner 
@@ -0,0 +1,290 @@
+// command-line arguments
+
+use crate::{output::*, tonemap::*, sampler::*, adaptive::*, bvh::*};
+
+use std::path::PathBuf;
+
+/// the scenes compiled in the binary
+pub static BUILTIN_SCENES: &[&str] = &[
+    "random", "two-spheres", "noise", "simple-light",
+    "triangles", "cornell", "cornell-smoke"
+];
+
+pub struct Options {
+    /// a built-in scene name, a .toml scene file or a .obj/.ply model
+    pub scene: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub samples: Option<i32>,
+    pub max_depth: Option<i32>,
+    /// standard output if missing
+    pub output: Option<PathBuf>,
+    pub format: ImageFormat,
+    pub display: DisplayTransform,
+    pub threads: Option<usize>,
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub bvh: BvhBuilder,
+    pub adaptive: Option<AdaptiveSettings>,
+    /// the samples per pixel as an image, with adaptive sampling
+    pub heatmap: Option<(PathBuf, ImageFormat)>
+}
+
+pub enum Command {
+    Render(Options),
+    Help
+}
+
+pub fn usage() -> String {
+    format!("Usage: rs-tracer [OPTIONS] [SCENE]
+
+SCENE is a built-in scene name, a .toml scene file or a .obj/.ply model.
+Built-in scenes: {}. Defaults to random.
+
+Options:
+  -W, --width <PIXELS>     image width, overrides the scene setting
+  -H, --height <PIXELS>    image height, overrides the scene setting
+  -s, --samples <COUNT>    samples per pixel, overrides the scene setting
+  -d, --max-depth <COUNT>  maximum number of ray bounces, overrides the scene setting
+      --scene <SCENE>      same as the positional SCENE argument
+  -o, --output <PATH>      where to write the image, standard output by default
+  -f, --format <FORMAT>    png, ppm (binary), ppm-ascii, or the float formats hdr,
+                           pfm, exr (zip compressed) and exr-uncompressed. By default
+                           guessed from the output extension, ppm-ascii on standard output
+      --tonemap <NAME>     clamp (default), reinhard, extended-reinhard, hable or aces,
+                           for the 8-bit formats only
+      --exposure <STOPS>   brightens or darkens the 8-bit formats, 0 by default
+      --white <RADIANCE>   brightness mapped to white by extended-reinhard and hable, 4 by default
+  -j, --threads <COUNT>    number of rendering threads, one per core by default
+      --sampler <NAME>     how the samples of a pixel get spread: independent,
+                           stratified, halton or sobol (default)
+      --adaptive <ERROR>   stop sampling a pixel once the relative error of its brightness
+                           is below ERROR, 0.02 for instance. --samples is the maximum then.
+                           Not with the stratified sampler
+      --min-samples <COUNT>  samples of every pixel with --adaptive, 16 by default
+      --heatmap <PATH>     with --adaptive, write the samples per pixel as an image, from
+                           blue for the fewest to red for the most
+      --bvh <BUILDER>      how the bounding volume hierarchy gets built: sah (default)
+                           or median
+      --seed <NUMBER>      seed for all the random numbers, 0 by default. The same
+                           seed always gives the same image
+  -h, --help               print this help", BUILTIN_SCENES.join(", "))
+}
+
+/// 16384 x 16384, the framebuffer alone takes gigabytes then
+pub const MAX_PIXELS: i32 = 1 << 28;
+
+/// the image must fit in memory
+pub fn check_image_size(width: i32, height: i32) -> Result<(), String> {
+    match width.checked_mul(height) {
+        Some(pixels) if pixels <= MAX_PIXELS => Ok(()),
+        _ => Err(format!("the image is too large: {}x{}, at most {} pixels", width, height, MAX_PIXELS))
+    }
+}
+
+fn parse_positive<T: std::str::FromStr + PartialOrd + Default>(flag: &str, value: &str) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(v) if v > T::default() => Ok(v),
+        _ => Err(format!("invalid value for {}: {}, expected a positive number", flag, value))
+    }
+}
+
+/// `args` doesn't include the program name
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut options = Options {
+        scene: "random".to_string(),
+        width: None,
+        height: None,
+        samples: None,
+        max_depth: None,
+        output: None,
+        format: ImageFormat::PpmAscii,
+        display: DisplayTransform::default(),
+        threads: None,
+        seed: 0,
+        sampler: SamplerKind::Sobol,
+        bvh: BvhBuilder::default(),
+        adaptive: None,
+        heatmap: None
+    };
+    let mut scene = None;
+    let mut format = None;
+    let (mut threshold, mut min_samples, mut heatmap) = (None, None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        // both '--width 800' and '--width=800' are accepted
+        let (flag, inline_value) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i+1..].to_string())),
+            _ => (arg.as_str(), None)
+        };
+        if flag == "-h" || flag == "--help" {
+            return Ok(Command::Help);
+        }
+        if !flag.starts_with('-') || flag == "-" {
+            if scene.is_some() {
+                return Err(format!("unexpected argument: {}", arg));
+            }
+            scene = Some(arg.to_string());
+            continue;
+        }
+        let mut value = || inline_value.clone().or_else(|| args.next().cloned())
+            .ok_or_else(|| format!("missing value for {}", flag));
+        match flag {
+            "-W" | "--width" => options.width = Some(parse_positive(flag, &value()?)?),
+            "-H" | "--height" => options.height = Some(parse_positive(flag, &value()?)?),
+            "-s" | "--samples" => options.samples = Some(parse_positive(flag, &value()?)?),
+            "-d" | "--max-depth" => options.max_depth = Some(parse_positive(flag, &value()?)?),
+            "-o" | "--output" => options.output = Some(PathBuf::from(value()?)),
+            "-f" | "--format" => {
+                let v = value()?;
+                format = Some(ImageFormat::from_name(&v).ok_or_else(
+                    || format!("unknown image format: {}, see --help", v))?);
+            },
+            "--tonemap" => {
+                let v = value()?;
+                options.display.tone_mapper = ToneMapper::from_name(&v).ok_or_else(
+                    || format!("unknown tone mapper: {}, see --help", v))?;
+            },
+            "--exposure" => {
+                let v = value()?;
+                options.display.exposure = v.parse::<f32>().ok().filter(|e| e.is_finite()).ok_or_else(
+                    || format!("invalid value for --exposure: {}, expected a number", v))?;
+            },
+            "--white" => options.display.white_point = parse_positive(flag, &value()?)?,
+            "-j" | "--threads" => options.threads = Some(parse_positive(flag, &value()?)?),
+            "--sampler" => {
+                let v = value()?;
+                options.sampler = SamplerKind::from_name(&v).ok_or_else(
+                    || format!("unknown sampler: {}, see --help", v))?;
+            },
+            "--adaptive" => threshold = Some(parse_positive(flag, &value()?)?),
+            "--min-samples" => min_samples = Some(parse_positive(flag, &value()?)?),
+            "--heatmap" => heatmap = Some(PathBuf::from(value()?)),
+            "--bvh" => {
+                let v = value()?;
+                options.bvh = BvhBuilder::from_name(&v).ok_or_else(
+                    || format!("unknown bvh builder: {}, see --help", v))?;
+            },
+            "--seed" => {
+                let v = value()?;
+                options.seed = v.parse().map_err(
+                    |_| format!("invalid value for --seed: {}, expected a number", v))?;
+            },
+            "--scene" => {
+                if scene.is_some() {
+                    return Err("the scene is given twice".to_string());
+                }
+                scene = Some(value()?);
+            },
+            _ => return Err(format!("unknown option: {}", flag))
+        }
+    }
+    // the scene may set the other side, main checks again then
+    check_image_size(options.width.unwrap_or(1), options.height.unwrap_or(1))?;
+    if let Some(scene) = scene {
+        let is_file = [".toml", ".obj", ".ply"].iter().any(|ext| scene.ends_with(ext));
+        if !is_file && !BUILTIN_SCENES.contains(&scene.as_str()) {
+            return Err(format!("unknown scene: {}", scene));
+        }
+        options.scene = scene;
+    }
+    match threshold {
+        // its strata are only complete with all the samples per pixel
+        Some(_) if options.sampler.batch_size().is_none() =>
+            return Err("--adaptive doesn't go with the stratified sampler".to_string()),
+        Some(threshold) => options.adaptive = Some(AdaptiveSettings {
+            threshold,
+            min_samples: min_samples.unwrap_or(16)
+        }),
+        None if min_samples.is_some() || heatmap.is_some() =>
+            return Err("--min-samples and --heatmap only go with --adaptive".to_string()),
+        None => {}
+    }
+    if let Some(path) = heatmap {
+        let format = ImageFormat::from_extension(&path).ok_or_else(
+            || format!("can't tell the image format of {}", path.display()))?;
+        options.heatmap = Some((path, format));
+    }
+    if let Some(format) = format {
+        options.format = format;
+    } else if let Some(output) = &options.output {
+        options.format = ImageFormat::from_extension(output).ok_or_else(
+            || format!("can't tell the image format of {}, use --format", output.display()))?;
+    }
+    Ok(Command::Render(options))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_parse_args() {
+        match parse(&["-W", "320", "--height=200", "cornell", "-o", "out.ppm", "--seed", "7"]) {
+            Ok(Command::Render(options)) => {
+                assert_eq!("cornell", options.scene);
+                assert_eq!(Some(320), options.width);
+                assert_eq!(Some(200), options.height);
+                assert_eq!(None, options.samples);
+                assert_eq!(Some(PathBuf::from("out.ppm")), options.output);
+                assert_eq!(7, options.seed);
+                assert_eq!(ImageFormat::Ppm, options.format);
+            },
+            _ => panic!("expected render options")
+        }
+        match parse(&["--tonemap", "aces", "--exposure=-1.5"]) {
+            Ok(Command::Render(options)) => {
+                assert_eq!(ToneMapper::Aces, options.display.tone_mapper);
+                assert_eq!(-1.5, options.display.exposure);
+            },
+            _ => panic!("expected render options")
+        }
+        match parse(&["--adaptive", "0.05", "--heatmap", "heat.png"]) {
+            Ok(Command::Render(options)) => {
+                let adaptive = options.adaptive.expect("adaptive settings");
+                assert_eq!(0.05, adaptive.threshold);
+                assert_eq!(16, adaptive.min_samples);
+                assert_eq!(Some((PathBuf::from("heat.png"), ImageFormat::Png)), options.heatmap);
+            },
+            _ => panic!("expected render options")
+        }
+        match parse(&[]) {
+            Ok(Command::Render(options)) => {
+                assert_eq!("random", options.scene);
+                assert_eq!(ImageFormat::PpmAscii, options.format);
+            },
+            _ => panic!("expected render options")
+        }
+        assert!(matches!(parse(&["--samples", "4", "--help"]), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse(&["--width"]).is_err());
+        assert!(parse(&["--width", "0"]).is_err());
+        assert!(parse(&["-W", "2000000000", "-H", "2000000000"]).is_err());
+        assert!(parse(&["-W", "16385", "-H", "16384"]).is_err());
+        assert!(parse(&["--width", "300000000"]).is_err());
+        assert!(parse(&["-W", "16384", "-H", "16384"]).is_ok());
+        assert_eq!(Err("the image is too large: 65536x65536, at most 268435456 pixels".to_string()),
+                   check_image_size(65536, 65536));
+        assert!(parse(&["--samples", "many"]).is_err());
+        assert!(parse(&["--threads=-2"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+        assert!(parse(&["no-such-scene"]).is_err());
+        assert!(parse(&["cornell", "noise"]).is_err());
+        assert!(parse(&["--tonemap", "filmic"]).is_err());
+        assert!(parse(&["--sampler", "random"]).is_err());
+        assert!(parse(&["--bvh", "kd-tree"]).is_err());
+        assert!(parse(&["--heatmap", "samples.png"]).is_err());
+        assert!(parse(&["--adaptive", "0.01", "--sampler", "stratified"]).is_err());
+        assert!(parse(&["--adaptive", "0.01", "--heatmap", "samples.gif"]).is_err());
+        assert!(parse(&["--exposure", "inf"]).is_err());
+        assert!(parse(&["-o", "image.gif"]).is_err());
+        assert!(parse(&["-o", "image.gif", "--format", "jpeg"]).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+// zlib streams (RFC 1950/1951) and the checksums needed by PNG
+
+/// base length for the length symbols 257 to 285
+static LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+static LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0
+];
+static DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+];
+static DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13
+];
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// how many earlier positions with the same hash we compare against
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// continues the crc of the bytes before `data`
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most we can sum before b can overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// deflate streams are packed starting from the least significant bit
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit_buffer: u32,
+    bit_count: u32
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bit_buffer |= value << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.bytes.push(self.bit_buffer as u8);
+            self.bit_buffer >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    /// huffman codes are the only values stored most significant bit first
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.bytes.push(self.bit_buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// the literal/length code of the fixed huffman block type
+fn fixed_literal_code(symbol: u32) -> (u32, u32) {
+    match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8)
+    }
+}
+
+/// index of the last table entry not above `value`
+fn symbol_index(base: &[u16], value: usize) -> usize {
+    base.iter().rposition(|&b| b as usize <= value).unwrap()
+}
+
+fn write_match(out: &mut BitWriter, length: usize, distance: usize) {
+    let l = symbol_index(&LENGTH_BASE, length);
+    let (code, code_length) = fixed_literal_code(257 + l as u32);
+    out.write_code(code, code_length);
+    out.write_bits((length - LENGTH_BASE[l] as usize) as u32, LENGTH_EXTRA_BITS[l] as u32);
+    let d = symbol_index(&DISTANCE_BASE, distance);
+    out.write_code(d as u32, 5);
+    out.write_bits((distance - DISTANCE_BASE[d] as usize) as u32, DISTANCE_EXTRA_BITS[d] as u32);
+}
+
+fn hash(data: &[u8]) -> usize {
+    let v = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (v.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// a single fixed huffman block, with LZ77 matches found through hash chains
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter { bytes: Vec::with_capacity(data.len() / 2), bit_buffer: 0, bit_count: 0 };
+    out.write_bits(1, 1); // last block
+    out.write_bits(1, 2); // fixed huffman codes
+    // head: most recent position for a hash, prev: the previous one with the same hash
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let insert = |pos: usize, head: &mut [usize], prev: &mut [usize]| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos..]);
+            prev[pos % WINDOW_SIZE] = head[h];
+            head[h] = pos;
+        }
+    };
+    let mut pos = 0;
+    while pos < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash(&data[pos..])];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = data[candidate..].iter().zip(&data[pos..pos + max_length])
+                    .take_while(|(a, b)| a == b).count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = pos - candidate;
+                    if length == max_length {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW_SIZE];
+                // older entries of the ring buffer get overwritten
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+        if best_length >= MIN_MATCH {
+            write_match(&mut out, best_length, best_distance);
+            for p in pos..pos + best_length {
+                insert(p, &mut head, &mut prev);
+            }
+            pos += best_length;
+        } else {
+            let (code, code_length) = fixed_literal_code(data[pos] as u32);
+            out.write_code(code, code_length);
+            insert(pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    let (code, code_length) = fixed_literal_code(256); // end of block
+    out.write_code(code, code_length);
+    out.finish()
+}
+
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32K window, default compression level
+    let mut result = vec![0x78, 0x9c];
+    result.extend(deflate(data));
+    result.extend(&adler32(data).to_be_bytes());
+    result
+}
+
+/// deflate streams are read the same way, least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buffer: u32,
+    bit_count: u32
+}
+
+impl<'a> BitReader<'a> {
+    /// at most 16 bits at a time
+    fn read_bits(&mut self, count: u32) -> Result<u32, String> {
+        while self.bit_count < count {
+            let byte = *self.data.get(self.pos).ok_or("the compressed data is truncated")?;
+            self.pos += 1;
+            self.bit_buffer |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buffer & ((1 << count) - 1);
+        self.bit_buffer >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// drops what's left of the current byte, which is all that the buffer holds
+    fn align_to_byte(&mut self) {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+    }
+}
+
+const MAX_CODE_LENGTH: usize = 15;
+
+/// a canonical huffman code, as the number of codes of each
+/// length and the symbols ordered by code
+struct Huffman {
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    symbols: Vec<u16>
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, String> {
+        let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        // incomplete codes are allowed, as for a single distance code
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = 2*left - count as i32;
+            if left < 0 {
+                return Err("invalid huffman code lengths".to_string());
+            }
+        }
+        let mut offsets = [0u16; MAX_CODE_LENGTH + 2];
+        for length in 1..=MAX_CODE_LENGTH {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; offsets[MAX_CODE_LENGTH + 1] as usize];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length > 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    /// one bit at a time: the codes of each length are consecutive numbers
+    fn decode(&self, input: &mut BitReader) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= input.read_bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid huffman code".to_string())
+    }
+}
+
+fn fixed_huffman_codes() -> (Huffman, Huffman) {
+    let lengths: Vec<u8> = (0..288).map(|symbol| fixed_literal_code(symbol).1 as u8).collect();
+    (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap())
+}
+
+/// the order in which the lengths of the code length code are stored
+static CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn dynamic_huffman_codes(input: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = input.read_bits(5)? as usize + 257;
+    let distance_count = input.read_bits(5)? as usize + 1;
+    let code_length_count = input.read_bits(4)? as usize + 4;
+    let mut code_length_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_length_lengths[symbol] = input.read_bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_length_lengths)?;
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_length_code.decode(input)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or("no code length to repeat")?;
+                (previous, 3 + input.read_bits(2)?)
+            },
+            17 => (0, 3 + input.read_bits(3)?),
+            _ => (0, 11 + input.read_bits(7)?)
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err("the code lengths overflow".to_string());
+    }
+    Ok((Huffman::new(&lengths[..literal_count])?, Huffman::new(&lengths[literal_count..])?))
+}
+
+fn inflate_block(input: &mut BitReader, literals: &Huffman, distances: &Huffman,
+                 out: &mut Vec<u8>) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(input)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let l = symbol - 257;
+        if l >= LENGTH_BASE.len() {
+            return Err(format!("invalid length symbol {}", symbol));
+        }
+        let length = LENGTH_BASE[l] as usize + input.read_bits(LENGTH_EXTRA_BITS[l] as u32)? as usize;
+        let d = distances.decode(input)? as usize;
+        if d >= DISTANCE_BASE.len() {
+            return Err(format!("invalid distance symbol {}", d));
+        }
+        let distance = DISTANCE_BASE[d] as usize + input.read_bits(DISTANCE_EXTRA_BITS[d] as u32)? as usize;
+        if distance > out.len() {
+            return Err("a match reaches before the start of the data".to_string());
+        }
+        // the match can overlap what it copies
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+fn inflate(input: &mut BitReader) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    loop {
+        let last = input.read_bits(1)? == 1;
+        match input.read_bits(2)? {
+            0 => {
+                input.align_to_byte();
+                let length = input.read_bits(16)?;
+                if input.read_bits(16)? != !length & 0xffff {
+                    return Err("corrupted stored block length".to_string());
+                }
+                let end = input.pos + length as usize;
+                let stored = input.data.get(input.pos..end).ok_or("the compressed data is truncated")?;
+                out.extend(stored);
+                input.pos = end;
+            },
+            1 => {
+                let (literals, distances) = fixed_huffman_codes();
+                inflate_block(input, &literals, &distances, &mut out)?;
+            },
+            2 => {
+                let (literals, distances) = dynamic_huffman_codes(input)?;
+                inflate_block(input, &literals, &distances, &mut out)?;
+            },
+            _ => return Err("invalid block type".to_string())
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 6 || data[0] & 0x0f != 8 || !(data[0] as u16 * 256 + data[1] as u16).is_multiple_of(31) {
+        return Err("not a zlib stream".to_string());
+    }
+    if data[1] & 0x20 != 0 {
+        return Err("zlib preset dictionaries aren't supported".to_string());
+    }
+    let mut input = BitReader { data: &data[2..], pos: 0, bit_buffer: 0, bit_count: 0 };
+    let out = inflate(&mut input)?;
+    let checksum = input.data.get(input.pos..input.pos + 4).ok_or("the zlib checksum is missing")?;
+    if adler32(&out).to_be_bytes() != checksum {
+        return Err("the zlib checksum doesn't match".to_string());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+        assert_eq!(crc32(b"123456789"), crc32_update(crc32(b"1234"), b"56789"));
+        assert_eq!(0x11e6_0398, adler32(b"Wikipedia"));
+    }
+
+    #[test]
+    fn test_zlib_compress() {
+        // three literals then a match of length 6 at distance 3
+        assert_eq!(vec![0x78, 0x9c, 0x4b, 0x4c, 0x4a, 0x86, 0x20, 0x00, 0x11, 0x3d, 0x03, 0x73],
+                   zlib_compress(b"abcabcabc"));
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        assert!(zlib_compress(&data).len() < 2000);
+    }
+
+    #[test]
+    fn test_zlib_decompress() {
+        let data: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        assert_eq!(data, zlib_decompress(&zlib_compress(&data)).unwrap());
+        // from zlib: a stored block, then a dynamic huffman block
+        assert_eq!(b"stored".to_vec(), zlib_decompress(&[
+            0x78, 0x01, 0x01, 0x06, 0x00, 0xf9, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x09, 0x3c, 0x02, 0x92
+        ]).unwrap());
+        assert_eq!(b"ahgjdabekjkdakbheeidbdhddfkiiahfgahgjdabekjkdakbhe".to_vec(), zlib_decompress(&[
+            0x78, 0xda, 0x5d, 0xca, 0x31, 0x12, 0x00, 0x30, 0x08, 0x02, 0xb0, 0xb7, 0xe2, 0xa1, 0x82, 0xfc,
+            0x7f, 0xef, 0xde, 0xcc, 0x81, 0xf6, 0x88, 0xea, 0x5c, 0x88, 0x94, 0xba, 0xcd, 0xa2, 0xc8, 0x89,
+            0x0d, 0xcd, 0xe2, 0x0f, 0x0f, 0xfb, 0xc7, 0x13, 0xec
+        ]).unwrap());
+        let mut corrupted = zlib_compress(b"abcabcabc");
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(zlib_decompress(&corrupted).is_err());
+        assert!(zlib_decompress(&zlib_compress(&data)[..100]).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+// minimal OpenEXR writer: scanline images with 32-bit float RGB channels
+
+use crate::{v3color::*, deflate::*};
+
+use std::io::{self, Write};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExrCompression {
+    None,
+    /// zlib over blocks of 16 scanlines
+    Zip
+}
+
+impl ExrCompression {
+    fn code(self) -> u8 {
+        match self {
+            ExrCompression::None => 0,
+            ExrCompression::Zip => 3
+        }
+    }
+
+    fn lines_per_block(self) -> usize {
+        match self {
+            ExrCompression::None => 1,
+            ExrCompression::Zip => 16
+        }
+    }
+}
+
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend(name.as_bytes());
+    header.push(0);
+    header.extend(kind.as_bytes());
+    header.push(0);
+    header.extend(&(value.len() as i32).to_le_bytes());
+    header.extend(value);
+}
+
+fn header(width: usize, height: usize, compression: ExrCompression) -> Vec<u8> {
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    let mut channels = Vec::new();
+    // channels must be sorted by name
+    for name in &["B", "G", "R"] {
+        channels.extend(name.as_bytes());
+        channels.push(0);
+        channels.extend(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        channels.extend(&[0, 0, 0, 0]); // pLinear and reserved
+        channels.extend(&1i32.to_le_bytes()); // x sampling
+        channels.extend(&1i32.to_le_bytes()); // y sampling
+    }
+    channels.push(0);
+    attribute(&mut header, "channels", "chlist", &channels);
+    attribute(&mut header, "compression", "compression", &[compression.code()]);
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1].iter()
+        .flat_map(|v| v.to_le_bytes().to_vec()).collect();
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]); // increasing y
+    attribute(&mut header, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut header, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    header.push(0);
+    header
+}
+
+/// before compression, ZIP splits the even and odd bytes
+/// then stores the differences between successive bytes
+fn zip_predictor(data: &[u8]) -> Vec<u8> {
+    let half = data.len().div_ceil(2);
+    let mut reordered = vec![0; data.len()];
+    for (i, &b) in data.iter().enumerate() {
+        reordered[if i % 2 == 0 { i / 2 } else { half + i / 2 }] = b;
+    }
+    for i in (1..reordered.len()).rev() {
+        reordered[i] = reordered[i].wrapping_sub(reordered[i - 1]).wrapping_add(128);
+    }
+    reordered
+}
+
+/// `pixels` has the rows top to bottom
+pub fn write_exr(out: &mut dyn Write, pixels: &[Vec<Color>], compression: ExrCompression) -> io::Result<()> {
+    let height = pixels.len();
+    let width = pixels.first().map_or(0, |row| row.len());
+    let header = header(width, height, compression);
+    let lines_per_block = compression.lines_per_block();
+
+    let mut chunks = Vec::new();
+    for (block, rows) in pixels.chunks(lines_per_block).enumerate() {
+        // each scanline has all the values of a channel, then the next channel
+        let mut data = Vec::with_capacity(rows.len() * width * 12);
+        for row in rows {
+            for channel in &[|c: &Color| c.b, |c: &Color| c.g, |c: &Color| c.r] {
+                for c in row {
+                    data.extend(&channel(c).to_le_bytes());
+                }
+            }
+        }
+        if compression == ExrCompression::Zip {
+            let compressed = zlib_compress(&zip_predictor(&data));
+            // readers take a chunk as uncompressed when it's not smaller
+            if compressed.len() < data.len() {
+                data = compressed;
+            }
+        }
+        let mut chunk = Vec::with_capacity(data.len() + 8);
+        chunk.extend(&((block * lines_per_block) as i32).to_le_bytes());
+        chunk.extend(&(data.len() as i32).to_le_bytes());
+        chunk.extend(data);
+        chunks.push(chunk);
+    }
+
+    out.write_all(&header)?;
+    // the offset table, from the start of the file
+    let mut offset = (header.len() + chunks.len() * 8) as u64;
+    for chunk in &chunks {
+        out.write_all(&offset.to_le_bytes())?;
+        offset += chunk.len() as u64;
+    }
+    for chunk in &chunks {
+        out.write_all(chunk)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_zip_predictor() {
+        // even bytes 1 2 3 then odd bytes 4 5, all one apart
+        assert_eq!(vec![1, 129, 129, 129, 129], zip_predictor(&[1, 4, 2, 5, 3]));
+    }
+}
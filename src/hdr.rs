@@ -0,0 +1,136 @@
+// float image formats: Radiance RGBE and portable float maps
+
+use crate::v3color::*;
+
+use std::io::{self, Write};
+
+/// the largest mantissa with the largest exponent
+const MAX_RGBE: f32 = 255.0 / 256.0 * 1.7014118e38; // 2^127
+
+/// shared 8-bit mantissas with a common exponent
+fn to_rgbe(c: &Color) -> [u8; 4] {
+    // NaNs are black, too bright is as bright as it gets
+    let representable = |x: f32| if x.is_nan() { 0.0 } else { x.clamp(0.0, MAX_RGBE) };
+    let c = Color { r: representable(c.r), g: representable(c.g), b: representable(c.b) };
+    let v = c.r.max(c.g).max(c.b);
+    if v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // v = m * 2^e with m in [0.5, 1)
+    let mut e = v.log2().floor() as i32 + 1;
+    if v / 2f32.powi(e) >= 1.0 {
+        e += 1;
+    } else if v / 2f32.powi(e) < 0.5 {
+        e -= 1;
+    }
+    let scale = 256.0 / 2f32.powi(e);
+    let to_byte = |x: f32| (x * scale) as u8;
+    [to_byte(c.r), to_byte(c.g), to_byte(c.b), (e + 128) as u8]
+}
+
+/// the run-length encoding of one component of a scanline:
+/// runs are 128+count then the byte, literal spans count then the bytes
+fn write_rle_component(out: &mut Vec<u8>, data: &[u8]) {
+    const MIN_RUN: usize = 4;
+    let mut cur = 0;
+    while cur < data.len() {
+        // look for the next run long enough to be worth encoding
+        let mut run_start = cur;
+        let mut run_count = 0;
+        let mut previous_run_count = 0;
+        while run_count < MIN_RUN && run_start < data.len() {
+            run_start += run_count;
+            previous_run_count = run_count;
+            run_count = 1;
+            while run_start + run_count < data.len() && run_count < 127
+                && data[run_start] == data[run_start + run_count] {
+                run_count += 1;
+            }
+        }
+        // a short run just before the long one
+        if previous_run_count > 1 && previous_run_count == run_start - cur {
+            out.push(128 + previous_run_count as u8);
+            out.push(data[cur]);
+            cur = run_start;
+        }
+        while cur < run_start {
+            let count = (run_start - cur).min(128);
+            out.push(count as u8);
+            out.extend(&data[cur..cur + count]);
+            cur += count;
+        }
+        if run_count >= MIN_RUN {
+            out.push(128 + run_count as u8);
+            out.push(data[run_start]);
+            cur += run_count;
+        }
+    }
+}
+
+/// `pixels` has the rows top to bottom
+pub fn write_radiance_hdr(out: &mut dyn Write, pixels: &[Vec<Color>]) -> io::Result<()> {
+    let height = pixels.len();
+    let width = pixels.first().map_or(0, |row| row.len());
+    write!(out, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+    let mut line = Vec::new();
+    for row in pixels {
+        let rgbe: Vec<[u8; 4]> = row.iter().map(to_rgbe).collect();
+        line.clear();
+        // the run-length encoding can't describe narrower or wider scanlines
+        if (8..0x8000).contains(&width) {
+            line.extend(&[2, 2, (width >> 8) as u8, width as u8]);
+            for component in 0..4 {
+                let data: Vec<u8> = rgbe.iter().map(|p| p[component]).collect();
+                write_rle_component(&mut line, &data);
+            }
+        } else {
+            line.extend(rgbe.iter().flatten());
+        }
+        out.write_all(&line)?;
+    }
+    out.flush()
+}
+
+/// `pixels` has the rows top to bottom
+pub fn write_pfm(out: &mut dyn Write, pixels: &[Vec<Color>]) -> io::Result<()> {
+    let height = pixels.len();
+    let width = pixels.first().map_or(0, |row| row.len());
+    // a negative scale means little endian
+    write!(out, "PF\n{} {}\n-1.0\n", width, height)?;
+    let mut line = Vec::with_capacity(width * 12);
+    // rows are stored bottom to top
+    for row in pixels.iter().rev() {
+        line.clear();
+        for c in row {
+            for v in &[c.r, c.g, c.b] {
+                line.extend(&v.to_le_bytes());
+            }
+        }
+        out.write_all(&line)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_rgbe() {
+        assert_eq!([128, 64, 0, 129], to_rgbe(&Color { r: 1.0, g: 0.5, b: 0.0 }));
+        assert_eq!([0, 0, 0, 0], to_rgbe(&Color { r: f32::NAN, g: -1.0, b: 0.0 }));
+        assert_eq!([128, 0, 64, 129], to_rgbe(&Color { r: 1.0, g: f32::NAN, b: 0.5 }));
+        let brightest = [255, 0, 255, 255];
+        assert_eq!(brightest, to_rgbe(&Color { r: f32::INFINITY, g: f32::NEG_INFINITY, b: f32::MAX }));
+        assert_eq!(brightest, to_rgbe(&Color { r: MAX_RGBE, g: 0.0, b: MAX_RGBE }));
+        assert_eq!([160, 128, 0, 131], to_rgbe(&Color { r: 5.0, g: 4.0, b: 0.0 }));
+        assert_eq!([0, 0, 0, 0], to_rgbe(&Color { r: 0.0, g: 0.0, b: 0.0 }));
+    }
+
+    #[test]
+    fn test_rle_component() {
+        let mut out = Vec::new();
+        write_rle_component(&mut out, &[1, 2, 3, 7, 7, 7, 7, 7, 4, 4]);
+        assert_eq!(vec![3, 1, 2, 3, 128+5, 7, 130, 4], out);
+    }
+}
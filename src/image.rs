@@ -0,0 +1,126 @@
+// images read from files, for textures
+
+use crate::{v3color::*, png::*};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    Format(String)
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => write!(f, "{}", e),
+            ImageError::Format(message) => write!(f, "{}", message)
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> ImageError {
+        ImageError::Io(e)
+    }
+}
+
+/// linear RGB, rows top to bottom
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>
+}
+
+impl Image {
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// the header numbers of a PPM file, which can be separated
+/// by any whitespace and comments
+fn ppm_number(data: &[u8], pos: &mut usize) -> Result<usize, String> {
+    loop {
+        match data.get(*pos) {
+            Some(c) if c.is_ascii_whitespace() => *pos += 1,
+            Some(b'#') => while data.get(*pos).is_some_and(|&c| c != b'\n') {
+                *pos += 1;
+            },
+            _ => break
+        }
+    }
+    let start = *pos;
+    while data.get(*pos).is_some_and(|c| c.is_ascii_digit()) {
+        *pos += 1;
+    }
+    std::str::from_utf8(&data[start..*pos]).unwrap().parse()
+        .map_err(|_| "the PPM file is truncated or invalid".to_string())
+}
+
+/// plain (P3) and binary (P6) PPM, same output as `read_png`
+fn read_ppm(data: &[u8]) -> Result<(usize, usize, Vec<f32>), String> {
+    let mut pos = 2;
+    let width = ppm_number(data, &mut pos)?;
+    let height = ppm_number(data, &mut pos)?;
+    let max = ppm_number(data, &mut pos)?;
+    if max == 0 || max > 65535 {
+        return Err(format!("invalid PPM maximum value {}", max));
+    }
+    // the header may be anything, the sizes must not overflow
+    let too_large = || format!("the PPM image is too large: {}x{}", width, height);
+    let count = width.checked_mul(height).and_then(|n| n.checked_mul(3)).ok_or_else(too_large)?;
+    let samples = if data.starts_with(b"P3") {
+        (0..count).map(|_| ppm_number(data, &mut pos)).collect::<Result<Vec<_>, _>>()?
+    } else {
+        // a single whitespace character before the binary data
+        let bytes_per_sample = if max < 256 { 1 } else { 2 };
+        let size = count.checked_mul(bytes_per_sample).ok_or_else(too_large)?;
+        let binary = data.get(pos + 1..).and_then(|d| d.get(..size))
+            .ok_or("the PPM file is truncated")?;
+        binary.chunks(bytes_per_sample)
+            .map(|s| s.iter().fold(0, |v, &b| v << 8 | b as usize))
+            .collect()
+    };
+    Ok((width, height, samples.iter().map(|&s| (s as f32 / max as f32).min(1.0)).collect()))
+}
+
+/// PNG or PPM, told apart by their first bytes
+pub fn load_image(path: &Path) -> Result<Image, ImageError> {
+    let data = fs::read(path)?;
+    let (width, height, values) = if data.starts_with(b"\x89PNG") {
+        read_png(&data)
+    } else if data.starts_with(b"P3") || data.starts_with(b"P6") {
+        read_ppm(&data)
+    } else {
+        Err("only PNG and PPM images are supported".to_string())
+    }.map_err(ImageError::Format)?;
+    if width == 0 || height == 0 {
+        return Err(ImageError::Format("the image is empty".to_string()));
+    }
+    // both formats store sRGB encoded values
+    let pixels = values.chunks(3)
+        .map(|c| Color { r: srgb_to_linear(c[0]), g: srgb_to_linear(c[1]), b: srgb_to_linear(c[2]) })
+        .collect();
+    Ok(Image { width, height, pixels })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_ppm() {
+        let expected = (2, 1, vec![0.0, 0.5, 1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(expected, read_ppm(b"P3\n# a comment\n2 1\n2\n0 1 2\n2 2 0\n").unwrap());
+        assert_eq!(expected, read_ppm(b"P6 2 1 2\n\x00\x01\x02\x02\x02\x00").unwrap());
+        assert_eq!(expected, read_ppm(b"P6 2 1 65534\n\x00\x00\x7f\xff\xff\xfe\xff\xfe\xff\xfe\x00\x00").unwrap());
+        assert!(read_ppm(b"P6 2 1 255\n\x00\x01\x02").is_err());
+        assert!(read_ppm(b"P3 2 1 255 0 1 2 3").is_err());
+        assert!(read_ppm(b"P6 4611686018427387904 4 255\n\x00").unwrap_err().contains("too large"));
+        assert!(read_ppm(b"P6 1537228672809129301 3 65535\n\x00").unwrap_err().contains("too large"));
+    }
+}
@@ -9,60 +9,76 @@ mod camera;
 mod material;
 mod bvh;
 mod texture;
+mod image;
 mod perlin;
+mod worley;
+mod mesh;
+mod obj;
+mod ply;
+mod transform;
+mod medium;
+mod scene;
+mod cli;
+mod deflate;
+mod png;
+mod hdr;
+mod exr;
+mod tonemap;
+mod rng;
+mod sampler;
+mod adaptive;
+mod output;
 use {
-    v3color::*, shapes::*, camera::*, 
-    material::*, bvh::*, texture::*, perlin::*
+    v3color::*, shapes::*,
+    material::*, bvh::*, texture::*, perlin::*,
+    transform::*, medium::*, scene::*, output::*, rng::*, sampler::*, adaptive::*,
+    camera::*
     };
 
 use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use rand::{prelude as random, Rng};
+use rand::Rng;
 use rayon::prelude::*;
 
-static WIDTH: i32 = 800;
-static HEIGHT: i32 = 400;
-static ANTIALIAS_SAMPLES: i32 = 100;
-
 static BLACK_V: V3 = V3 { x: 0.0, y: 0.0, z: 0.0};
 
-fn print_color(col: Color) -> String {
-    let to_component = |c| (255.99 * f32::sqrt(c)) as i32;
-    format!("{} {} {}", 
-        to_component(col.r),
-        to_component(col.g),
-        to_component(col.b))
-}
-
-fn closest_hit<'a>(objects: &'a [Box<Shape>], ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+fn closest_hit<'a>(objects: &'a [Box<dyn Shape>], ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
     objects
         .iter()
         .flat_map(|o| o.hit(ray, t_range))
         .min_by(|o1, o2| f32_cmp(o1.t, o2.t))
 }
 
-fn color_for_ray(objects: &[Box<Shape>], ray: &Ray, depth: i32) -> Color {
-    _color_for_ray(objects, ray, depth).to_color()
+/// everything rays bounce around in
+struct World {
+    objects: Vec<Box<dyn Shape>>,
+    background: Background,
+    max_depth: i32
+}
+
+fn color_for_ray(world: &World, ray: &Ray, depth: i32, sampler: &mut dyn Sampler) -> Color {
+    _color_for_ray(world, ray, depth, sampler).to_color()
 }
 
-fn _color_for_ray(objects: &[Box<Shape>], ray: &Ray, depth: i32) -> V3 {
-    if depth >= 50 {
+fn _color_for_ray(world: &World, ray: &Ray, depth: i32, sampler: &mut dyn Sampler) -> V3 {
+    if depth >= world.max_depth {
         return BLACK_V;
     }
-    match closest_hit(objects, ray, &(0.001..std::f32::MAX)) {
+    match closest_hit(&world.objects, ray, &(0.001..f32::MAX)) {
         Some(r) => {
-            r.material.scatter(ray, &r)
-                .map_or_else(|| BLACK_V, |scatter_info| {
-                    scatter_info.attenuation.to_v3()
-                        * _color_for_ray(objects, &scatter_info.scattered, depth+1)
+            let emitted = r.material.emitted(r.u, r.v, &r.p).to_v3();
+            r.material.scatter(ray, &r, sampler)
+                .map_or(emitted, |scatter_info| {
+                    emitted + scatter_info.attenuation.to_v3()
+                        * _color_for_ray(world, &scatter_info.scattered, depth+1, sampler)
                 })
         }
-        None => {
-            let unit_direction = ray.direction.unit();
-            let t = 0.5 * (unit_direction.y + 1.0);
-            ((1.0-t) * V3 { x: 1.0, y: 1.0, z: 1.0 }
-                + t*V3 { x: 0.5, y: 0.7, z: 1.0 })
-        }
+        None => world.background.color(ray)
     }
 }
 
@@ -70,6 +86,7 @@ fn two_spheres_scene() -> Vec<Box<Shape>> {
     let checker = || Box::new(SphericalCheckerTexture {
         even: Box::new(ConstantTexture { color: Color { r: 0.2, g: 0.3, b: 0.1 } }),
         odd: Box::new(ConstantTexture { color: Color { r: 0.9, g: 0.9, b: 0.9 } }),
+        frequency: 10.0
     });
     vec![
         Box::new(Sphere {
@@ -89,12 +106,34 @@ fn two_spheres_scene() -> Vec<Box<Shape>> {
     ]
 }
 
-fn noise_two_spheres_scene() -> Vec<Box<Shape>> {
-    let noise_t = || Box::new(NoiseTexture::new());
+fn noise_two_spheres_scene(rng: &mut Pcg32) -> Vec<Box<dyn Shape>> {
+    let mut noise_t = || Box::new(NoiseTexture { perlin: Perlin::new(rng), frequency: 4.0, octaves: 1 });
     vec![
+        Box::new(InfinitePlane {
+            point: V3 { x: 0.0, y: 0.0, z: 0.0 },
+            normal: V3 { x: 0.0, y: 1.0, z: 0.0 },
+            material: Box::new(Lambertian {
+                albedo: noise_t()
+            })
+        }),
         Box::new(Sphere {
-            center: V3 { x: 0.0, y: -1000.0, z: 0.0},
-            radius: 1000.0,
+            center: V3 { x: 0.0, y: 2.0, z: 0.0},
+            radius: 2.0,
+            material: Box::new(Lambertian {
+                albedo: noise_t()
+            })
+        })
+    ]
+}
+
+/// a couple of spheres lit only by a spherical
+/// area light, in the dark.
+fn simple_light_scene(rng: &mut Pcg32) -> Vec<Box<dyn Shape>> {
+    let mut noise_t = || Box::new(NoiseTexture { perlin: Perlin::new(rng), frequency: 4.0, octaves: 1 });
+    vec![
+        Box::new(InfinitePlane {
+            point: V3 { x: 0.0, y: 0.0, z: 0.0 },
+            normal: V3 { x: 0.0, y: 1.0, z: 0.0 },
             material: Box::new(Lambertian {
                 albedo: noise_t()
             })
@@ -105,20 +144,169 @@ fn noise_two_spheres_scene() -> Vec<Box<Shape>> {
             material: Box::new(Lambertian {
                 albedo: noise_t()
             })
+        }),
+        Box::new(Sphere {
+            center: V3 { x: 0.0, y: 7.0, z: 0.0},
+            radius: 2.0,
+            material: Box::new(DiffuseLight {
+                emit: Box::new(ConstantTexture { color: Color { r: 4.0, g: 4.0, b: 4.0 } })
+            })
         })
     ]
 }
 
-fn scene() -> Vec<Box<Shape>> {
-    let mut rng = random::thread_rng();
+/// the walls and the light of the cornell box, without anything inside
+fn cornell_room() -> Vec<Box<dyn Shape>> {
+    let lambertian = |r, g, b| Box::new(Lambertian {
+        albedo: Box::new(ConstantTexture { color: Color { r, g, b } })
+    });
+    vec![
+        Box::new(FlipNormals { shape: Box::new(YzRect {
+            y0: 0.0, y1: 555.0, z0: 0.0, z1: 555.0, k: 555.0,
+            material: lambertian(0.12, 0.45, 0.15)
+        })}),
+        Box::new(YzRect {
+            y0: 0.0, y1: 555.0, z0: 0.0, z1: 555.0, k: 0.0,
+            material: lambertian(0.65, 0.05, 0.05)
+        }),
+        Box::new(XzRect {
+            x0: 213.0, x1: 343.0, z0: 227.0, z1: 332.0, k: 554.0,
+            material: Box::new(DiffuseLight {
+                emit: Box::new(ConstantTexture { color: Color { r: 15.0, g: 15.0, b: 15.0 } })
+            })
+        }),
+        Box::new(FlipNormals { shape: Box::new(XzRect {
+            x0: 0.0, x1: 555.0, z0: 0.0, z1: 555.0, k: 555.0,
+            material: lambertian(0.73, 0.73, 0.73)
+        })}),
+        Box::new(XzRect {
+            x0: 0.0, x1: 555.0, z0: 0.0, z1: 555.0, k: 0.0,
+            material: lambertian(0.73, 0.73, 0.73)
+        }),
+        Box::new(FlipNormals { shape: Box::new(XyRect {
+            x0: 0.0, x1: 555.0, y0: 0.0, y1: 555.0, k: 555.0,
+            material: lambertian(0.73, 0.73, 0.73)
+        })})
+    ]
+}
+
+/// the two blocks standing in the cornell box
+fn cornell_blocks() -> (Box<dyn Shape>, Box<dyn Shape>) {
+    let lambertian = || Box::new(Lambertian {
+        albedo: Box::new(ConstantTexture { color: Color { r: 0.73, g: 0.73, b: 0.73 } })
+    });
+    (Box::new(Transformed::new(
+        Arc::new(BoxShape {
+            pmin: V3 { x: 0.0, y: 0.0, z: 0.0 },
+            pmax: V3 { x: 165.0, y: 165.0, z: 165.0 },
+            material: lambertian()
+        }),
+        M4::translation(&V3 { x: 130.0, y: 0.0, z: 65.0 })
+            * M4::rotation(&V3 { x: 0.0, y: 1.0, z: 0.0 }, -18.0))),
+     Box::new(Transformed::new(
+        Arc::new(BoxShape {
+            pmin: V3 { x: 0.0, y: 0.0, z: 0.0 },
+            pmax: V3 { x: 165.0, y: 330.0, z: 165.0 },
+            material: lambertian()
+        }),
+        M4::translation(&V3 { x: 265.0, y: 0.0, z: 295.0 })
+            * M4::rotation(&V3 { x: 0.0, y: 1.0, z: 0.0 }, 15.0))))
+}
+
+fn cornell_box_scene() -> Vec<Box<dyn Shape>> {
+    let mut objects = cornell_room();
+    let (short_block, tall_block) = cornell_blocks();
+    objects.push(short_block);
+    objects.push(tall_block);
+    objects
+}
+
+/// the cornell box blocks, made of smoke
+fn cornell_smoke_scene() -> Vec<Box<dyn Shape>> {
+    let mut objects = cornell_room();
+    let (short_block, tall_block) = cornell_blocks();
+    objects.push(Box::new(ConstantMedium::new(short_block, 0.01,
+        Box::new(ConstantTexture { color: Color { r: 1.0, g: 1.0, b: 1.0 } }))));
+    objects.push(Box::new(ConstantMedium::new(tall_block, 0.01,
+        Box::new(ConstantTexture { color: Color { r: 0.0, g: 0.0, b: 0.0 } }))));
+    objects
+}
+
+/// same shape twice, flat and smooth shaded
+fn triangles_scene() -> Vec<Box<dyn Shape>> {
+    let octahedron = |center: V3, smooth: bool, material: Arc<dyn Material>| {
+        let mut faces: Vec<Box<dyn Shape>> = vec![];
+        for &sx in &[-1.0, 1.0] {
+            for &sy in &[-1.0, 1.0] {
+                for &sz in &[-1.0, 1.0] {
+                    let a = V3 { x: sx, y: 0.0, z: 0.0 };
+                    let mut b = V3 { x: 0.0, y: sy, z: 0.0 };
+                    let mut c = V3 { x: 0.0, y: 0.0, z: sz };
+                    // keep the winding order counter-clockwise seen from outside
+                    if sx*sy*sz < 0.0 {
+                        std::mem::swap(&mut b, &mut c);
+                    }
+                    faces.push(Box::new(Triangle {
+                        vertices: [center + a, center + b, center + c],
+                        normals: if smooth { Some([a, b, c]) } else { None },
+                        uvs: None,
+                        material: material.clone()
+                    }));
+                }
+            }
+        }
+        faces
+    };
+    let mut objects: Vec<Box<dyn Shape>> = vec![
+        Box::new(InfinitePlane {
+            point: V3 { x: 0.0, y: 0.0, z: 0.0 },
+            normal: V3 { x: 0.0, y: 1.0, z: 0.0 },
+            material: Box::new(Lambertian {
+                albedo: Box::new(ConstantTexture { color: Color { r: 0.5, g: 0.5, b: 0.5 } })
+            })
+        })
+    ];
+    objects.extend(octahedron(V3 { x: 0.0, y: 1.0, z: -1.5 }, false, Arc::new(Lambertian {
+        albedo: Box::new(ConstantTexture { color: Color { r: 0.8, g: 0.3, b: 0.1 } })
+    })));
+    objects.extend(octahedron(V3 { x: 0.0, y: 1.0, z: 1.5 }, true, Arc::new(Lambertian {
+        albedo: Box::new(ConstantTexture { color: Color { r: 0.1, g: 0.3, b: 0.8 } })
+    })));
+    objects
+}
+
+fn is_mesh_path(path: &str) -> bool {
+    path.ends_with(".obj") || path.ends_with(".ply")
+}
+
+fn mesh_scene(path: &str) -> Vec<Box<dyn Shape>> {
+    let mesh = if path.ends_with(".ply") {
+        ply::load_ply(Path::new(path), None)
+    } else {
+        obj::load_obj(Path::new(path))
+    };
+    match mesh {
+        Ok(mesh) => {
+            eprintln!("Loaded {} triangles from {}", mesh.triangle_count(), path);
+            vec![Box::new(mesh)]
+        },
+        Err(e) => {
+            eprintln!("Error loading {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn scene(rng: &mut Pcg32) -> Vec<Box<dyn Shape>> {
     let checker = Box::new(CheckerTexture {
         even: Box::new(ConstantTexture { color: Color { r: 0.2, g: 0.3, b: 0.1 } }),
         odd: Box::new(ConstantTexture { color: Color { r: 0.9, g: 0.9, b: 0.9 } }),
+        frequency: 10.0
     });
-    let mut objects: Vec<Box<Shape>> = vec![
-        Box::new(Sphere {
-            center: V3 { x: 0.0, y: -1000.0, z: 0.0},
-            radius: 1000.0,
+    let mut objects: Vec<Box<dyn Shape>> = vec![
+        Box::new(InfinitePlane {
+            point: V3 { x: 0.0, y: 0.0, z: 0.0 },
+            normal: V3 { x: 0.0, y: 1.0, z: 0.0 },
             material: Box::new(Lambertian { albedo: checker })
         })
     ];
@@ -201,63 +389,315 @@ fn scene() -> Vec<Box<Shape>> {
     objects
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    println!("P3\n{} {}\n255", WIDTH, HEIGHT);
-
-    let objects = vec![BvhNode::compute_shapes_bvh(
-        match args[1].as_ref() {
-            "--two-spheres" => two_spheres_scene(),
-            "--noise" => noise_two_spheres_scene(),
-            _ => scene()
-        },
-        &(0.001..std::f32::MAX))];
-    // let objects = scene();
-
+fn default_camera() -> CameraSettings {
     let look_from = V3 {x: 10.0, y: 1.8, z: 2.6};
-    let look_at = V3 {x: 0.0, y: 0.5, z: 0.0};
-    let camera = Camera::new(CameraParams {
-        look_from: &look_from,
-        look_at: &look_at,
-        vup: &V3 {x: 0.0, y: 1.0, z: 0.0},
-        focus_dist: (look_from - V3 {x: 4.0, y: 1.0, z: 0.0}).length(),
+    CameraSettings {
+        look_from,
+        look_at: V3 {x: 0.0, y: 0.5, z: 0.0},
+        vup: V3 {x: 0.0, y: 1.0, z: 0.0},
+        vert_fov_deg: 20.0,
         aperture: 0.05,
-        aspect: WIDTH as f32 / HEIGHT as f32,
+        focus_dist: (look_from - V3 {x: 4.0, y: 1.0, z: 0.0}).length(),
+        aspect: None,
+        time1: 0.0,
+        time2: 1.0
+    }
+}
+
+fn cornell_camera() -> CameraSettings {
+    CameraSettings {
+        look_from: V3 {x: 278.0, y: 278.0, z: -800.0},
+        look_at: V3 {x: 278.0, y: 278.0, z: 0.0},
+        vup: V3 {x: 0.0, y: 1.0, z: 0.0},
+        vert_fov_deg: 40.0,
+        aperture: 0.0,
+        focus_dist: 800.0,
+        aspect: None,
+        time1: 0.0,
+        time2: 1.0
+    }
+}
+
+/// frames the whole bounding box
+fn framing_camera(bbox: &Aabb) -> CameraSettings {
+    let center = 0.5 * (bbox.min + bbox.max);
+    let distance = 3.0 * (bbox.max - bbox.min).length();
+    CameraSettings {
+        look_from: center + distance * V3 {x: 0.0, y: 0.3, z: 1.0}.unit(),
+        look_at: center,
+        vup: V3 {x: 0.0, y: 1.0, z: 0.0},
         vert_fov_deg: 20.0,
+        aperture: 0.0,
+        focus_dist: distance,
+        aspect: None,
         time1: 0.0,
         time2: 1.0
-    });
+    }
+}
+
+/// the scenes built in the binary, or a model file
+/// `seed` drives the random parts of the scenes
+fn builtin_scene(name: &str, seed: u64) -> Scene {
+    let mut rng = Pcg32::new(seed, SCENE_STREAM);
+    let black = || Background::Solid(Color { r: 0.0, g: 0.0, b: 0.0 });
+    let (objects, background, camera) = match name {
+        "two-spheres" => (two_spheres_scene(), Background::Sky, default_camera()),
+        "noise" => (noise_two_spheres_scene(&mut rng), Background::Sky, default_camera()),
+        "simple-light" => (simple_light_scene(&mut rng), black(), default_camera()),
+        "triangles" => (triangles_scene(), Background::Sky, default_camera()),
+        "cornell" => (cornell_box_scene(), black(), cornell_camera()),
+        "cornell-smoke" => (cornell_smoke_scene(), black(), cornell_camera()),
+        path if is_mesh_path(path) => {
+            let objects = mesh_scene(path);
+            let camera = framing_camera(&objects[0].bounding_box(&(0.0..1.0)).expect("meshes are bounded"));
+            (objects, Background::Sky, camera)
+        },
+        _ => (scene(&mut rng), Background::Sky, default_camera())
+    };
+    Scene { objects, background, camera, settings: RenderSettings::default() }
+}
 
+/// the color of each pixel and how many samples it took, rows top to bottom.
+/// The samples only depend on the pixel and the seed, so the image is
+/// the same whatever the number of threads.
+fn render(world: &World, camera: &Camera, settings: &RenderSettings, min_samples: i32,
+          options: &cli::Options, show_progress: bool) -> Vec<Vec<(Color, i32)>> {
+    let (width, height, samples) = (settings.width, settings.height, settings.samples);
+    // stopping within a batch would leave its samples unevenly spread
+    let batch = options.sampler.batch_size().map_or(samples, |b| b as i32);
     let rendered_rows = AtomicUsize::new(0);
 
-    eprint!("Rendered {:3}%", 0);
+    if show_progress {
+        eprint!("Rendered {:3}%", 0);
+    }
     // use par_iter to render rows in a multithread manner using the rayon library.
-    let row_cols = (0..HEIGHT).rev().collect::<Vec<_>>().par_iter().map(|&j| {
-        let mut rng = random::thread_rng();
-        let row_cols = (0..WIDTH).map(|i| {
-            let mut col_vec = V3 { x: 0.0, y: 0.0, z: 0.0 };
-            for _ in 0..ANTIALIAS_SAMPLES {
-                let u = (i as f32 + rng.gen::<f32>()) / WIDTH as f32;
-                let v = (j as f32 + rng.gen::<f32>()) / HEIGHT as f32;
-                let ray = camera.get_ray(u, v);
-                let cur_col = color_for_ray(&objects, &ray, 0);
-                col_vec.x += cur_col.r;
-                col_vec.y += cur_col.g;
-                col_vec.z += cur_col.b;
+    let row_cols = (0..height).rev().collect::<Vec<_>>().par_iter().map(|&j| {
+        let mut sampler = options.sampler.sampler(options.seed, samples as u32);
+        let row_cols = (0..width).map(|i| {
+            let mut stats = PixelStats::new();
+            for s in 0..samples {
+                if s >= min_samples && s % batch == 0 && options.adaptive.as_ref().is_some_and(|a| stats.converged(a)) {
+                    break;
+                }
+                // samples only depend on the pixel and their index, so that
+                // the image doesn't depend on the thread scheduling
+                sampler.start_sample(i as u32, j as u32, s as u32);
+                let (du, dv) = sampler.get_2d();
+                let u = (i as f32 + du) / width as f32;
+                let v = (j as f32 + dv) / height as f32;
+                let ray = camera.get_ray(u, v, sampler.as_mut());
+                stats.add(&color_for_ray(world, &ray, 0, sampler.as_mut()));
             }
-            (col_vec / ANTIALIAS_SAMPLES as f32).to_color()
+            (stats.color(), stats.count)
         }).collect::<Vec<_>>();
         let rendered = rendered_rows.fetch_add(1, Ordering::SeqCst)+1;
-        if rendered % 10 == 0 {
-            eprint!("\rRendered {:3}%", (rendered as i32) * 100 / HEIGHT);
+        if show_progress && rendered.is_multiple_of(10) {
+            eprint!("\rRendered {:3}%", (rendered as i32) * 100 / height);
         }
         row_cols
     }).collect::<Vec<_>>();
-    for row_col in row_cols {
-        for pixel in row_col {
-            println!("{}", print_color(pixel));
+    if show_progress {
+        eprint!("\r");
+    }
+    row_cols
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match cli::parse_args(&args) {
+        Ok(cli::Command::Render(options)) => options,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::usage());
+            return;
+        },
+        Err(e) => {
+            eprintln!("Error: {}\nTry --help for more information.", e);
+            process::exit(2);
+        }
+    };
+
+    let Scene { objects, background, camera, mut settings } = if options.scene.ends_with(".toml") {
+        load_scene(Path::new(&options.scene), options.seed).unwrap_or_else(|e| {
+            eprintln!("Error in {}: {}", options.scene, e);
+            process::exit(1);
+        })
+    } else {
+        builtin_scene(&options.scene, options.seed)
+    };
+    settings.width = options.width.unwrap_or(settings.width);
+    settings.height = options.height.unwrap_or(settings.height);
+    settings.samples = options.samples.unwrap_or(settings.samples);
+    settings.max_depth = options.max_depth.unwrap_or(settings.max_depth);
+    let (width, height, samples) = (settings.width, settings.height, settings.samples);
+    if let Err(e) = cli::check_image_size(width, height) {
+        eprintln!("Error: {}", e);
+        process::exit(2);
+    }
+
+    if let Some(threads) = options.threads {
+        if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+            eprintln!("Error: can't set up {} threads: {}", threads, e);
+            process::exit(1);
+        }
+    }
+
+    // open the output before rendering, not to lose the render on a bad path
+    let mut out: Box<dyn Write> = match &options.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("Error: can't create {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        },
+        None => Box::new(BufWriter::new(io::stdout()))
+    };
+    let mut heatmap_out = options.heatmap.as_ref().map(|(path, format)| match File::create(path) {
+        Ok(file) => (path, *format, BufWriter::new(file)),
+        Err(e) => {
+            eprintln!("Error: can't create {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    });
+
+    // with adaptive sampling the sample count is the maximum
+    let min_samples = options.adaptive.as_ref().map_or(samples, |a| a.min_samples.min(samples));
+
+    // boxes of moving shapes cover where they are while the shutter is open
+    let shutter = camera.time1..camera.time2;
+    let camera = camera.to_camera(&settings);
+    let world = World {
+        objects: vec![Box::new(Bvh::build(objects, &shutter, options.bvh))],
+        background,
+        max_depth: settings.max_depth
+    };
+
+    let row_cols = render(&world, &camera, &settings, min_samples, &options, true);
+    let image: Vec<Vec<Color>> = row_cols.iter()
+        .map(|row| row.iter().map(|(c, _)| *c).collect()).collect();
+    if let Err(e) = write_image(&mut out, options.format, &options.display, &image) {
+        eprintln!("Error writing the image: {}", e);
+        process::exit(1);
+    }
+    if options.adaptive.is_some() {
+        let total: i64 = row_cols.iter().flatten().map(|&(_, n)| n as i64).sum();
+        eprintln!("{:.1} samples per pixel on average", total as f64 / (width as f64 * height as f64));
+    }
+    if let Some((path, format, heatmap_file)) = &mut heatmap_out {
+        let heatmap: Vec<Vec<Color>> = row_cols.iter()
+            .map(|row| row.iter().map(|&(_, n)| heatmap_color(n, min_samples, samples)).collect())
+            .collect();
+        let display = tonemap::DisplayTransform::default();
+        if let Err(e) = write_image(heatmap_file, *format, &display, &heatmap) {
+            eprintln!("Error writing {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_same_image_whatever_the_threads() {
+        // smoke, for the scatter distances drawn in the media too
+        let Scene { objects, background, camera, mut settings } = builtin_scene("cornell-smoke", 0);
+        settings.width = 24;
+        settings.height = 24;
+        settings.samples = 8;
+        let shutter = camera.time1..camera.time2;
+        let camera = camera.to_camera(&settings);
+        let world = World {
+            objects: vec![Box::new(Bvh::build(objects, &shutter, BvhBuilder::default()))],
+            background,
+            max_depth: settings.max_depth
+        };
+        for args in &[vec![], vec!["--sampler", "independent", "--adaptive", "0.1", "--min-samples", "2"]] {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            let options = match cli::parse_args(&args) {
+                Ok(cli::Command::Render(options)) => options,
+                _ => panic!("invalid arguments {:?}", args)
+            };
+            let min_samples = options.adaptive.as_ref().map_or(settings.samples, |a| a.min_samples);
+            let images: Vec<_> = [1, 4].iter().map(|&threads| {
+                rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap()
+                    .install(|| render(&world, &camera, &settings, min_samples, &options, false))
+            }).collect();
+            assert!(images[0].iter().flatten().any(|(c, _)| c.r > 0.0));
+            assert!(images[0] == images[1], "{:?}: the images differ", args);
+        }
+    }
+
+    #[test]
+    fn test_lights_in_the_dark() {
+        let gray = |l: f32| Box::new(ConstantTexture { color: Color { r: l, g: l, b: l } });
+        let sphere_at = |z: f32, material: Box<dyn Material>| Box::new(Sphere {
+            center: V3 { x: 0.0, y: 0.0, z },
+            radius: 1.0,
+            material
+        }) as Box<dyn Shape>;
+        let dark_world = |objects| World {
+            objects,
+            background: Background::Solid(Color { r: 0.0, g: 0.0, b: 0.0 }),
+            max_depth: 50
+        };
+        let ray = |direction: V3| Ray { origin: V3 { x: 0.0, y: 0.0, z: 0.0 }, direction, time: 0.0 };
+        let forward = ray(V3 { x: 0.0, y: 0.0, z: -1.0 });
+        let up = ray(V3 { x: 0.0, y: 1.0, z: 0.0 });
+        let black = Color { r: 0.0, g: 0.0, b: 0.0 };
+        let mut sampler = IndependentSampler::new(0);
+
+        // the light is seen as it is, lights don't scatter
+        let lit = dark_world(vec![sphere_at(-5.0, Box::new(DiffuseLight { emit: gray(4.0) }))]);
+        assert_eq!(Color { r: 4.0, g: 4.0, b: 4.0 }, color_for_ray(&lit, &forward, 0, &mut sampler));
+        assert_eq!(black, color_for_ray(&lit, &up, 0, &mut sampler));
+        // out of bounces, even looking at the light
+        assert_eq!(black, color_for_ray(&lit, &forward, 50, &mut sampler));
+
+        // without a light every path ends in the black background
+        let unlit = dark_world(vec![sphere_at(-5.0, Box::new(Lambertian { albedo: gray(0.9) }))]);
+        for _ in 0..100 {
+            assert_eq!(black, color_for_ray(&unlit, &forward, 0, &mut sampler));
+        }
+    }
+
+    /// cargo test --release -- --ignored --nocapture bvh
+    ///
+    /// the timings are test output, only shown with --nocapture;
+    /// the test itself checks that both trees see the same hits.
+    #[test]
+    #[ignore]
+    fn benchmark_bvh_builders() {
+        let settings = RenderSettings { width: 400, height: 200, ..Default::default() };
+        let camera = default_camera().to_camera(&settings);
+        let mut hit_counts = vec![];
+        for &builder in &[BvhBuilder::Median, BvhBuilder::Sah] {
+            let start = Instant::now();
+            let bvh = Bvh::build(scene(&mut Pcg32::new(0, SCENE_STREAM)), &(0.0..1.0), builder);
+            let build_time = start.elapsed();
+            let mut sampler = SamplerKind::Sobol.sampler(0, 16);
+            let start = Instant::now();
+            let mut hits = 0;
+            for j in 0..settings.height {
+                for i in 0..settings.width {
+                    for s in 0..16 {
+                        sampler.start_sample(i as u32, j as u32, s);
+                        let (du, dv) = sampler.get_2d();
+                        let ray = camera.get_ray((i as f32 + du) / settings.width as f32,
+                                                 (j as f32 + dv) / settings.height as f32, sampler.as_mut());
+                        if bvh.hit(&ray, &(0.001..f32::MAX)).is_some() {
+                            hits += 1;
+                        }
+                    }
+                }
+            }
+            let rays = settings.width * settings.height * 16;
+            println!("{:?}: built in {:?}, {:.0} rays/s, {} hits",
+                     builder, build_time, rays as f64 / start.elapsed().as_secs_f64(), hits);
+            hit_counts.push(hits);
         }
+        assert_eq!(hit_counts[0], hit_counts[1]);
     }
-    eprint!("\r");
 }
@@ -1,44 +1,59 @@
-use crate::{v3color::*, shapes::*, texture::*};
+use crate::{v3color::*, shapes::*, texture::*, sampler::*};
 
-use rand::{prelude as random, Rng};
+use std::f32::consts::PI;
+use std::sync::Arc;
 
 pub struct MaterialScatterInfo {
     pub attenuation: Color,
     pub scattered: Ray
 }
 
-pub trait Material: Sync {
-    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<MaterialScatterInfo>;
+pub trait Material: Send + Sync {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<MaterialScatterInfo>;
+
+    /// light given off by the material itself. Most materials
+    /// don't emit anything, hence the black default.
+    fn emitted(&self, _u: f32, _v: f32, _p: &V3) -> Color {
+        Color { r: 0.0, g: 0.0, b: 0.0 }
+    }
 }
 
-fn random_in_unit_sphere() -> V3 {
-    let mut rng = random::thread_rng();
-    let mut p;
-    let unit = V3 { x: 1.0, y: 1.0, z: 1.0};
-    loop {
-        p = 2.0 * V3 { 
-            x: rng.gen::<f32>(),
-            y: rng.gen::<f32>(),
-            z: rng.gen::<f32>()
-        } - unit;
-        if p.squared_length() < 1.0 { break p; }
+/// shared materials, for instance the one of a mesh
+impl<M: Material + ?Sized> Material for Arc<M> {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<MaterialScatterInfo> {
+        (**self).scatter(ray_in, hit_record, sampler)
+    }
+
+    fn emitted(&self, u: f32, v: f32, p: &V3) -> Color {
+        (**self).emitted(u, v, p)
     }
 }
 
+/// a direction from two dimensions, then a radius
+/// from a third one to fill the volume uniformly
+fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> V3 {
+    let (u, v) = sampler.get_2d();
+    let z = 1.0 - 2.0*u;
+    let r = f32::sqrt(f32::max(0.0, 1.0 - z*z));
+    let phi = 2.0*PI*v;
+    let radius = sampler.get_1d().cbrt();
+    radius * V3 { x: r*phi.cos(), y: r*phi.sin(), z }
+}
+
 pub struct Lambertian {
     pub albedo: Box<Texture>
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _ray_in: &Ray, hit_record: &HitRecord) -> Option<MaterialScatterInfo> {
-        let target = hit_record.p + hit_record.normal + random_in_unit_sphere();
+    fn scatter(&self, _ray_in: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<MaterialScatterInfo> {
+        let target = hit_record.p + hit_record.normal + random_in_unit_sphere(sampler);
         Some(MaterialScatterInfo {
             scattered: Ray {
                 origin: hit_record.p, 
                 direction: target - hit_record.p,
                 time: _ray_in.time
             },
-            attenuation: self.albedo.value(&hit_record.p)
+            attenuation: self.albedo.value(hit_record.u, hit_record.v, &hit_record.p)
         })
     }
 }
@@ -49,14 +64,14 @@ pub struct Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<MaterialScatterInfo> {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<MaterialScatterInfo> {
         let reflected = V3::reflect(
             &ray_in.direction.unit(), 
             &hit_record.normal);
         Some(MaterialScatterInfo {
             scattered: Ray {
                 origin: hit_record.p,
-                direction: reflected + self.fuzz*random_in_unit_sphere(),
+                direction: reflected + self.fuzz*random_in_unit_sphere(sampler),
                 time: ray_in.time
             },
             attenuation: self.albedo
@@ -85,24 +100,22 @@ fn schlick(cosine: f32, ref_idx: f32) -> f32 {
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord) -> Option<MaterialScatterInfo> {
-        let mut rng = random::thread_rng();
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<MaterialScatterInfo> {
         let reflected = || V3::reflect(
             &ray_in.direction.unit(), 
             &hit_record.normal);
-        let (outward_normal, ni_over_nt, cosine_factor) = 
-            if V3::dot(&ray_in.direction, &hit_record.normal) > 0.0 {
-                (-hit_record.normal, self.ref_idx, self.ref_idx)
-            } else {
-                (hit_record.normal, 1.0 / self.ref_idx, -1.0)
-            };
+        let (ni_over_nt, cosine_factor) = if hit_record.front_face {
+            (1.0 / self.ref_idx, 1.0)
+        } else {
+            (self.ref_idx, self.ref_idx)
+        };
 
         let refract_direction_fn = |refracted| {
-            let cosine = cosine_factor 
+            let cosine = -cosine_factor
                 * V3::dot(&ray_in.direction, &hit_record.normal)
                 / ray_in.direction.length();
             let reflect_prob = schlick(cosine, self.ref_idx);
-            if rng.gen::<f32>() < reflect_prob {
+            if sampler.get_1d() < reflect_prob {
                 reflected()
             } else {
                 refracted
@@ -113,11 +126,68 @@ impl Material for Dielectric {
             scattered: Ray {
                 origin: hit_record.p,
                 direction:
-                    refract(&ray_in.direction, &outward_normal, ni_over_nt)
+                    refract(&ray_in.direction, &hit_record.normal, ni_over_nt)
                         .map_or_else(reflected, refract_direction_fn),
                 time: ray_in.time
             },
             attenuation: Color { r: 1.0, g: 1.0, b: 1.0 }
         })
     }
-}
\ No newline at end of file
+}
+
+/// phase function of participating media: scatters
+/// uniformly in all directions.
+pub struct Isotropic {
+    pub albedo: Box<dyn Texture>
+}
+
+impl Material for Isotropic {
+    fn scatter(&self, ray_in: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<MaterialScatterInfo> {
+        Some(MaterialScatterInfo {
+            scattered: Ray {
+                origin: hit_record.p,
+                direction: random_in_unit_sphere(sampler),
+                time: ray_in.time
+            },
+            attenuation: self.albedo.value(hit_record.u, hit_record.v, &hit_record.p)
+        })
+    }
+}
+
+/// area light: doesn't scatter anything, only emits
+/// according to its texture.
+pub struct DiffuseLight {
+    pub emit: Box<dyn Texture>
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray_in: &Ray, _hit_record: &HitRecord, _sampler: &mut dyn Sampler) -> Option<MaterialScatterInfo> {
+        None
+    }
+
+    fn emitted(&self, u: f32, v: f32, p: &V3) -> Color {
+        self.emit.value(u, v, p)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diffuse_light() {
+        let light = DiffuseLight { emit: Box::new(ConstantTexture { color: Color { r: 4.0, g: 2.0, b: 1.0 } }) };
+        let p = V3 { x: 1.0, y: 2.0, z: 3.0 };
+        assert_eq!(Color { r: 4.0, g: 2.0, b: 1.0 }, light.emitted(0.5, 0.5, &p));
+        let ray = Ray { origin: V3 { x: 0.0, y: 0.0, z: 0.0 }, direction: p, time: 0.0 };
+        let hit = HitRecord {
+            t: 1.0, p, normal: V3 { x: -1.0, y: 0.0, z: 0.0 }, front_face: true,
+            u: 0.5, v: 0.5, material: &light
+        };
+        assert!(light.scatter(&ray, &hit, &mut IndependentSampler::new(0)).is_none());
+        // other materials don't emit anything
+        let black = Color { r: 0.0, g: 0.0, b: 0.0 };
+        assert_eq!(black, Dielectric { ref_idx: 1.5 }.emitted(0.5, 0.5, &p));
+        assert_eq!(black, Metal { albedo: black, fuzz: 0.0 }.emitted(0.5, 0.5, &p));
+    }
+}
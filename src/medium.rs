@@ -0,0 +1,123 @@
+// participating media: fog, smoke...
+
+use crate::{v3color::*, shapes::*, material::*, texture::*, bvh::*, rng::*};
+
+use rand::Rng;
+
+/// volume of constant density inside the boundary shape.
+/// A ray going through it may scatter at any point, the
+/// thicker the medium the likelier.
+pub struct ConstantMedium {
+    boundary: Box<dyn Shape>,
+    neg_inv_density: f32,
+    phase_function: Box<dyn Material>
+}
+
+impl ConstantMedium {
+    /// the boundary must be a closed, convex shape
+    pub fn new(boundary: Box<dyn Shape>, density: f32, albedo: Box<dyn Texture>) -> ConstantMedium {
+        ConstantMedium {
+            boundary,
+            neg_inv_density: -1.0 / density,
+            phase_function: Box::new(Isotropic { albedo })
+        }
+    }
+}
+
+impl Shape for ConstantMedium {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        // where the ray enters and exits the boundary, even behind the
+        // origin: rays scattered inside the medium start within it
+        let entry = self.boundary.hit(ray, &(f32::NEG_INFINITY..f32::INFINITY))?;
+        let exit = self.boundary.hit(ray, &(entry.t+0.0001..f32::INFINITY))?;
+        let t_entry = f32::max(f32::max(entry.t, t_range.start), 0.0);
+        let t_exit = f32::min(exit.t, t_range.end);
+        if t_entry >= t_exit {
+            return None;
+        }
+        let ray_length = ray.direction.length();
+        let distance_inside = (t_exit - t_entry) * ray_length;
+        // a deliberate exception to drawing everything from the sample's
+        // sampler: hit() doesn't get one, and threading it through every
+        // shape for this draw isn't worth it. The ray only depends on the
+        // pixel, the sample and the seed, so its hash gives a reproducible
+        // number. It isn't stratified like the sampler's, and identical
+        // rays get the same distance, which in practice only happens for
+        // the same sample.
+        let mut rng = Pcg32::new(hash_floats(&[
+            ray.origin.x, ray.origin.y, ray.origin.z,
+            ray.direction.x, ray.direction.y, ray.direction.z, ray.time]), 0);
+        let hit_distance = self.neg_inv_density * f32::ln(rng.gen::<f32>());
+        if hit_distance > distance_inside {
+            return None;
+        }
+        let t = t_entry + hit_distance / ray_length;
+        Some(HitRecord {
+            t,
+            p: ray.point_at_parameter(t),
+            // meaningless inside a volume
+            normal: V3 { x: 1.0, y: 0.0, z: 0.0 },
+            front_face: true,
+            u: 0.0,
+            v: 0.0,
+            material: &*self.phase_function
+        })
+    }
+
+    fn bounding_box(&self, t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        self.boundary.bounding_box(t_range)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fog(density: f32) -> ConstantMedium {
+        ConstantMedium::new(Box::new(Sphere {
+            center: V3 {x: 0.0, y: 0.0, z: 0.0},
+            radius: 1.0,
+            material: Box::new(Dielectric { ref_idx: 1.0 })
+        }), density, Box::new(ConstantTexture { color: Color { r: 0.5, g: 0.5, b: 0.5 } }))
+    }
+
+    fn ray(origin_x: f32, origin_z: f32) -> Ray {
+        Ray {
+            origin: V3 {x: origin_x, y: 0.0, z: origin_z},
+            direction: V3 {x: 0.0, y: 0.0, z: 2.0},
+            time: 0.0
+        }
+    }
+
+    #[test]
+    fn test_empty_medium() {
+        let empty = fog(0.0);
+        for i in 0..100 {
+            let x = i as f32 / 100.0 - 0.5;
+            assert!(empty.hit(&ray(x, -5.0), &(0.001..f32::MAX)).is_none());
+            assert!(empty.hit(&ray(x, 0.0), &(0.001..f32::MAX)).is_none());
+        }
+    }
+
+    #[test]
+    fn test_dense_medium() {
+        let range = 0.001..f32::MAX;
+        let dense = fog(1000.0);
+        for i in 0..100 {
+            let x = i as f32 / 100.0 - 0.5;
+            let entry = f32::sqrt(1.0 - x * x);
+            // the boundary is crossed between t = (5 - entry) / 2 and (5 + entry) / 2
+            let hit = dense.hit(&ray(x, -5.0), &range).expect("should scatter inside the fog");
+            assert!(hit.t >= (5.0 - entry) / 2.0 && hit.t <= (5.0 + entry) / 2.0, "t = {}", hit.t);
+            assert!(hit.p.length() <= 1.0 + 1e-5);
+            assert!(hit.t - (5.0 - entry) / 2.0 < 0.01);
+            // from inside, it scatters ahead of the origin
+            let hit = dense.hit(&ray(x, 0.0), &range).expect("should scatter inside the fog");
+            assert!(hit.t >= range.start && hit.t <= entry / 2.0, "t = {}", hit.t);
+        }
+        // outside of the boundary, or out of the t range
+        assert!(dense.hit(&ray(1.5, -5.0), &range).is_none());
+        assert!(dense.hit(&ray(0.0, -5.0), &(0.001..1.0)).is_none());
+        assert!(dense.hit(&ray(0.0, 5.0), &range).is_none());
+    }
+}
@@ -0,0 +1,77 @@
+// triangle meshes, as loaded from model files
+
+use crate::{v3color::*, shapes::*, bvh::*, material::*, texture::*};
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+    NoGeometry
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            LoadError::NoGeometry => write!(f, "the file contains no triangles")
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> LoadError {
+        LoadError::Io(e)
+    }
+}
+
+pub fn parse_error<T>(line: usize, message: String) -> Result<T, LoadError> {
+    Err(LoadError::Parse { line, message })
+}
+
+/// used when the model file doesn't specify any material
+pub fn default_mesh_material() -> Arc<dyn Material> {
+    Arc::new(Lambertian {
+        albedo: Box::new(ConstantTexture { color: Color { r: 0.8, g: 0.8, b: 0.8 } })
+    })
+}
+
+/// a bunch of triangles, with their own bounding volume hierarchy
+pub struct Mesh {
+    bvh: Bvh,
+    triangle_count: usize
+}
+
+impl Mesh {
+    pub fn new(triangles: Vec<Triangle>) -> Result<Mesh, LoadError> {
+        if triangles.is_empty() {
+            return Err(LoadError::NoGeometry);
+        }
+        let triangle_count = triangles.len();
+        let shapes = triangles.into_iter()
+            .map(|t| Box::new(t) as Box<dyn Shape>)
+            .collect();
+        Ok(Mesh {
+            bvh: Bvh::build(shapes, &(0.0..1.0), BvhBuilder::default()),
+            triangle_count
+        })
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangle_count
+    }
+}
+
+impl Shape for Mesh {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        self.bvh.hit(ray, t_range)
+    }
+
+    fn bounding_box(&self, t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        self.bvh.bounding_box(t_range)
+    }
+}
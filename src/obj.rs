@@ -0,0 +1,327 @@
+// Wavefront OBJ models and their MTL material libraries
+// http://paulbourke.net/dataformats/obj/
+// http://paulbourke.net/dataformats/mtl/
+
+use crate::{v3color::*, shapes::*, material::*, texture::*, mesh::*};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+type Materials = HashMap<String, Arc<dyn Material>>;
+
+pub fn load_obj(path: &Path) -> Result<Mesh, LoadError> {
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    parse_obj(BufReader::new(File::open(path)?), |mtl_name| {
+        parse_mtl(BufReader::new(File::open(base_dir.join(mtl_name))?))
+    })
+}
+
+fn parse_floats(line_nr: usize, args: &[&str]) -> Result<Vec<f32>, LoadError> {
+    args.iter()
+        .map(|a| a.parse::<f32>().or_else(
+            |_| parse_error(line_nr, format!("invalid number: {}", a))))
+        .collect()
+}
+
+fn parse_v3(line_nr: usize, args: &[&str]) -> Result<V3, LoadError> {
+    match parse_floats(line_nr, args)?.as_slice() {
+        // positions may have a fourth 'w' component, which we ignore
+        [x, y, z] | [x, y, z, _] => Ok(V3 { x: *x, y: *y, z: *z }),
+        _ => parse_error(line_nr, format!("expected 3 coordinates, got {}", args.len()))
+    }
+}
+
+/// OBJ indices start at 1, and negative indices are
+/// relative to the end of the list read so far.
+fn resolve_index(line_nr: usize, index: &str, count: usize) -> Result<usize, LoadError> {
+    let i = index.parse::<i64>().or_else(
+        |_| parse_error(line_nr, format!("invalid index: {}", index)))?;
+    let resolved = if i < 0 { count as i64 + i } else { i - 1 };
+    if resolved < 0 || resolved >= count as i64 {
+        return parse_error(line_nr, format!("index {} out of range", i));
+    }
+    Ok(resolved as usize)
+}
+
+struct FaceVertex {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>
+}
+
+/// parses 'v', 'v/vt', 'v//vn' or 'v/vt/vn'
+fn parse_face_vertex(line_nr: usize, spec: &str, counts: (usize, usize, usize)) -> Result<FaceVertex, LoadError> {
+    let (position_count, uv_count, normal_count) = counts;
+    let mut parts = spec.split('/');
+    let optional_index = |part: Option<&str>, count| match part {
+        Some(p) if !p.is_empty() => resolve_index(line_nr, p, count).map(Some),
+        _ => Ok(None)
+    };
+    Ok(FaceVertex {
+        position: resolve_index(line_nr, parts.next().unwrap_or(""), position_count)?,
+        uv: optional_index(parts.next(), uv_count)?,
+        normal: optional_index(parts.next(), normal_count)?
+    })
+}
+
+fn parse_obj<R: BufRead>(reader: R, mut load_mtl: impl FnMut(&str) -> Result<Materials, LoadError>)
+        -> Result<Mesh, LoadError> {
+    let mut positions: Vec<V3> = vec![];
+    let mut normals: Vec<V3> = vec![];
+    let mut uvs: Vec<(f32, f32)> = vec![];
+    let mut materials: Materials = HashMap::new();
+    let mut current_material = default_mesh_material();
+    let mut triangles = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line_nr = i+1;
+        let line = line?;
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue
+        };
+        let args: Vec<&str> = tokens.collect();
+        match keyword {
+            "v" => positions.push(parse_v3(line_nr, &args)?),
+            "vn" => normals.push(parse_v3(line_nr, &args)?.unit()),
+            "vt" => match parse_floats(line_nr, &args)?.as_slice() {
+                [u] => uvs.push((*u, 0.0)),
+                [u, v, ..] => uvs.push((*u, *v)),
+                _ => return parse_error(line_nr, "expected texture coordinates".to_string())
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return parse_error(line_nr, format!("a face needs at least 3 vertices, got {}", args.len()));
+                }
+                let face = args.iter()
+                    .map(|a| parse_face_vertex(line_nr, a, (positions.len(), uvs.len(), normals.len())))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // polygons are split in a fan around their first vertex
+                for k in 1..face.len()-1 {
+                    let vs = [&face[0], &face[k], &face[k+1]];
+                    triangles.push(Triangle {
+                        vertices: [positions[vs[0].position], positions[vs[1].position], positions[vs[2].position]],
+                        normals: match (vs[0].normal, vs[1].normal, vs[2].normal) {
+                            (Some(n0), Some(n1), Some(n2)) => Some([normals[n0], normals[n1], normals[n2]]),
+                            _ => None
+                        },
+                        uvs: match (vs[0].uv, vs[1].uv, vs[2].uv) {
+                            (Some(t0), Some(t1), Some(t2)) => Some([uvs[t0], uvs[t1], uvs[t2]]),
+                            _ => None
+                        },
+                        material: current_material.clone()
+                    });
+                }
+            },
+            "mtllib" => {
+                for mtl_name in args {
+                    let mtl_materials = load_mtl(mtl_name).or_else(
+                        |e| parse_error(line_nr, format!("loading {}: {}", mtl_name, e)))?;
+                    materials.extend(mtl_materials);
+                }
+            },
+            "usemtl" => {
+                let name = args.join(" ");
+                current_material = match materials.get(&name) {
+                    Some(material) => material.clone(),
+                    None => return parse_error(line_nr, format!("unknown material: {}", name))
+                };
+            },
+            // groups, objects, smoothing groups, lines...
+            // don't matter for rendering
+            _ => {}
+        }
+    }
+    Mesh::new(triangles)
+}
+
+/// the subset of the MTL description we can map to our materials
+struct MtlDescription {
+    diffuse: Color,
+    specular: Color,
+    emission: Color,
+    shininess: f32,
+    ior: f32,
+    dissolve: f32,
+    illum: u32
+}
+
+impl Default for MtlDescription {
+    fn default() -> MtlDescription {
+        MtlDescription {
+            diffuse: Color { r: 0.8, g: 0.8, b: 0.8 },
+            specular: Color { r: 0.0, g: 0.0, b: 0.0 },
+            emission: Color { r: 0.0, g: 0.0, b: 0.0 },
+            shininess: 0.0,
+            ior: 1.5,
+            dissolve: 1.0,
+            illum: 2
+        }
+    }
+}
+
+fn is_black(c: &Color) -> bool {
+    c.r <= 0.0 && c.g <= 0.0 && c.b <= 0.0
+}
+
+impl MtlDescription {
+    fn to_material(&self) -> Arc<dyn Material> {
+        if !is_black(&self.emission) {
+            return Arc::new(DiffuseLight {
+                emit: Box::new(ConstantTexture { color: self.emission })
+            });
+        }
+        match self.illum {
+            // the illumination models with refraction
+            4 | 6 | 7 | 9 => Arc::new(Dielectric { ref_idx: self.ior }),
+            _ if self.dissolve < 1.0 => Arc::new(Dielectric { ref_idx: self.ior }),
+            // the illumination models with ray-traced reflection
+            3 | 5 | 8 => Arc::new(Metal {
+                albedo: if is_black(&self.specular) { self.diffuse } else { self.specular },
+                // map the phong exponent to a roughness
+                fuzz: f32::min(1.0, f32::sqrt(2.0 / (self.shininess + 2.0)))
+            }),
+            _ => Arc::new(Lambertian {
+                albedo: Box::new(ConstantTexture { color: self.diffuse })
+            })
+        }
+    }
+}
+
+fn parse_color(line_nr: usize, args: &[&str]) -> Result<Color, LoadError> {
+    match parse_floats(line_nr, args)?.as_slice() {
+        [c] => Ok(Color { r: *c, g: *c, b: *c }),
+        [r, g, b] => Ok(Color { r: *r, g: *g, b: *b }),
+        _ => parse_error(line_nr, format!("expected 1 or 3 color components, got {}", args.len()))
+    }
+}
+
+fn parse_float(line_nr: usize, args: &[&str]) -> Result<f32, LoadError> {
+    match parse_floats(line_nr, args)?.as_slice() {
+        [f] => Ok(*f),
+        _ => parse_error(line_nr, format!("expected a single number, got {}", args.len()))
+    }
+}
+
+fn parse_mtl<R: BufRead>(reader: R) -> Result<Materials, LoadError> {
+    let mut materials: Materials = HashMap::new();
+    let mut current: Option<(String, MtlDescription)> = None;
+    for (i, line) in reader.lines().enumerate() {
+        let line_nr = i+1;
+        let line = line?;
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue
+        };
+        let args: Vec<&str> = tokens.collect();
+        if keyword == "newmtl" {
+            if let Some((name, desc)) = current.take() {
+                materials.insert(name, desc.to_material());
+            }
+            current = Some((args.join(" "), MtlDescription::default()));
+            continue;
+        }
+        let desc = match current.as_mut() {
+            Some((_, desc)) => desc,
+            None => return parse_error(line_nr, format!("{} before any newmtl", keyword))
+        };
+        match keyword {
+            "Kd" => desc.diffuse = parse_color(line_nr, &args)?,
+            "Ks" => desc.specular = parse_color(line_nr, &args)?,
+            "Ke" => desc.emission = parse_color(line_nr, &args)?,
+            "Ns" => desc.shininess = parse_float(line_nr, &args)?,
+            "Ni" => desc.ior = parse_float(line_nr, &args)?,
+            "d" => desc.dissolve = parse_float(line_nr, &args)?,
+            "Tr" => desc.dissolve = 1.0 - parse_float(line_nr, &args)?,
+            "illum" => desc.illum = args.first().and_then(|a| a.parse().ok()).map_or_else(
+                || parse_error(line_nr, format!("invalid illumination model: {}", args.join(" "))),
+                Ok)?,
+            // ambient color, texture maps...
+            _ => {}
+        }
+    }
+    if let Some((name, desc)) = current {
+        materials.insert(name, desc.to_material());
+    }
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn no_mtl(name: &str) -> Result<Materials, LoadError> {
+        panic!("unexpected mtllib {}", name)
+    }
+
+    #[test]
+    fn test_parse_quad() {
+        let mesh = parse_obj("
+            # a unit quad in the XY plane
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0
+            vt 0 0
+            vt 1 0
+            vt 1 1
+            vt 0 1
+            vn 0 0 1
+            f -4/1/1 -3/2/1 -2/3/1 -1/4/1
+        ".as_bytes(), no_mtl).unwrap();
+        assert_eq!(2, mesh.triangle_count());
+        let hit = mesh.hit(&Ray {
+            origin: V3 {x: 0.75, y: 0.25, z: 1.0},
+            direction: V3 {x: 0.0, y: 0.0, z: -1.0},
+            time: 0.0
+        }, &(0.001..f32::MAX)).expect("should hit the quad");
+        assert_eq!(1.0, hit.t);
+        assert_eq!(V3 {x: 0.0, y: 0.0, z: 1.0}, hit.normal);
+        assert!((hit.u - 0.75).abs() < 1e-6 && (hit.v - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_parse_materials() {
+        let mesh = parse_obj("
+            mtllib lights.mtl
+            v 0 0 0
+            v 1 0 0
+            v 0 1 0
+            usemtl light
+            f 1 2 3
+        ".as_bytes(), |name| {
+            assert_eq!("lights.mtl", name);
+            parse_mtl("
+                newmtl light
+                Kd 0 0 0
+                Ke 4 4 4
+            ".as_bytes())
+        }).unwrap();
+        let hit = mesh.hit(&Ray {
+            origin: V3 {x: 0.25, y: 0.25, z: 1.0},
+            direction: V3 {x: 0.0, y: 0.0, z: -1.0},
+            time: 0.0
+        }, &(0.001..f32::MAX)).expect("should hit the triangle");
+        assert_eq!(Color { r: 4.0, g: 4.0, b: 4.0 }, hit.material.emitted(hit.u, hit.v, &hit.p));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        match parse_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n".as_bytes(), no_mtl) {
+            Err(LoadError::Parse { line: 4, .. }) => {},
+            _ => panic!("expected an error on line 4")
+        }
+        match parse_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl gold\nf 1 2 3\n".as_bytes(), no_mtl) {
+            Err(LoadError::Parse { line: 4, message }) => assert_eq!("unknown material: gold", message),
+            _ => panic!("expected an unknown material error on line 4")
+        }
+        match parse_obj("v 0 0 0\n".as_bytes(), no_mtl) {
+            Err(LoadError::NoGeometry) => {},
+            _ => panic!("expected a no geometry error")
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// writing the rendered image to disk
+
+use crate::{v3color::*, png::*, hdr::*, exr::*, tonemap::*};
+
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    /// binary PPM (P6)
+    Ppm,
+    /// plain text PPM (P3)
+    PpmAscii,
+    /// Radiance RGBE
+    Hdr,
+    /// portable float map
+    Pfm,
+    Exr(ExrCompression)
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "png" => Some(ImageFormat::Png),
+            "ppm" => Some(ImageFormat::Ppm),
+            "ppm-ascii" => Some(ImageFormat::PpmAscii),
+            "hdr" => Some(ImageFormat::Hdr),
+            "pfm" => Some(ImageFormat::Pfm),
+            "exr" => Some(ImageFormat::Exr(ExrCompression::Zip)),
+            "exr-uncompressed" => Some(ImageFormat::Exr(ExrCompression::None)),
+            _ => None
+        }
+    }
+
+    pub fn from_extension(path: &Path) -> Option<ImageFormat> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "ppm" | "pnm" => Some(ImageFormat::Ppm),
+            "hdr" => Some(ImageFormat::Hdr),
+            "pfm" => Some(ImageFormat::Pfm),
+            "exr" => Some(ImageFormat::Exr(ExrCompression::Zip)),
+            _ => None
+        }
+    }
+}
+
+/// `pixels` has the rows top to bottom
+fn to_rgb8(pixels: &[Vec<Color>], display: &DisplayTransform) -> Vec<u8> {
+    let to_component = |c: f32| (255.0 * c + 0.5) as u8;
+    pixels.iter().flatten()
+        .map(|c| display.apply(c))
+        .flat_map(|c| vec![to_component(c.r), to_component(c.g), to_component(c.b)])
+        .collect()
+}
+
+/// `pixels` has the rows top to bottom, with linear radiance that
+/// float formats store as is. 8-bit formats go through `display`.
+pub fn write_image(out: &mut dyn Write, format: ImageFormat,
+                   display: &DisplayTransform, pixels: &[Vec<Color>]) -> io::Result<()> {
+    let height = pixels.len();
+    let width = pixels.first().map_or(0, |row| row.len());
+    match format {
+        ImageFormat::Hdr => write_radiance_hdr(out, pixels)?,
+        ImageFormat::Pfm => write_pfm(out, pixels)?,
+        ImageFormat::Exr(compression) => write_exr(out, pixels, compression)?,
+        ImageFormat::Png => write_png(out, width as u32, height as u32, &to_rgb8(pixels, display))?,
+        ImageFormat::Ppm => {
+            write!(out, "P6\n{} {}\n255\n", width, height)?;
+            out.write_all(&to_rgb8(pixels, display))?;
+        },
+        ImageFormat::PpmAscii => {
+            writeln!(out, "P3\n{} {}\n255", width, height)?;
+            for pixel in to_rgb8(pixels, display).chunks(3) {
+                writeln!(out, "{} {} {}", pixel[0], pixel[1], pixel[2])?;
+            }
+        }
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deflate::*;
+
+    use std::convert::TryInto;
+    use std::fs::{self, File};
+
+    fn read_i32(data: &[u8], at: usize) -> i32 {
+        i32::from_le_bytes([data[at], data[at+1], data[at+2], data[at+3]])
+    }
+
+    fn read_f32(data: &[u8], at: usize) -> f32 {
+        f32::from_le_bytes([data[at], data[at+1], data[at+2], data[at+3]])
+    }
+
+    /// reads back what write_exr writes: float B, G, R channels, uncompressed
+    /// or zipped by blocks of 16 lines. Returns the compression code and the
+    /// pixels top to bottom.
+    fn read_exr(data: &[u8], width: usize, height: usize) -> (u8, Vec<Vec<Color>>) {
+        assert_eq!(&[0x76, 0x2f, 0x31, 0x01], &data[..4]);
+        let mut at = 8;
+        let mut compression = None;
+        let c_string = |at: usize| {
+            let end = at + data[at..].iter().position(|&b| b == 0).unwrap();
+            (std::str::from_utf8(&data[at..end]).unwrap(), end + 1)
+        };
+        loop {
+            let (name, next) = c_string(at);
+            if name.is_empty() {
+                at = next;
+                break;
+            }
+            let (_, next) = c_string(next);
+            let size = read_i32(data, next) as usize;
+            if name == "compression" {
+                compression = Some(data[next + 4]);
+            }
+            at = next + 4 + size;
+        }
+        let compression = compression.expect("no compression attribute");
+        let lines_per_block = if compression == 3 { 16 } else { 1 };
+        let blocks = height.div_ceil(lines_per_block);
+        let mut pixels = vec![];
+        for block in 0..blocks {
+            let offset = u64::from_le_bytes(data[at + block*8..at + block*8 + 8].try_into().unwrap()) as usize;
+            assert_eq!((block * lines_per_block) as i32, read_i32(data, offset));
+            let size = read_i32(data, offset + 4) as usize;
+            let rows = lines_per_block.min(height - block * lines_per_block);
+            let mut chunk = data[offset + 8..offset + 8 + size].to_vec();
+            if chunk.len() < rows * width * 12 {
+                // undo the predictor, then put the even and odd bytes back together
+                let mut predicted = zlib_decompress(&chunk).unwrap();
+                for i in 1..predicted.len() {
+                    predicted[i] = predicted[i - 1].wrapping_add(predicted[i]).wrapping_sub(128);
+                }
+                let half = predicted.len().div_ceil(2);
+                chunk = (0..predicted.len())
+                    .map(|i| predicted[if i % 2 == 0 { i / 2 } else { half + i / 2 }])
+                    .collect();
+            }
+            assert_eq!(rows * width * 12, chunk.len());
+            for row in chunk.chunks(width * 12) {
+                pixels.push((0..width).map(|x| Color {
+                    b: read_f32(row, x * 4),
+                    g: read_f32(row, (width + x) * 4),
+                    r: read_f32(row, (2 * width + x) * 4)
+                }).collect());
+            }
+        }
+        (compression, pixels)
+    }
+
+    #[test]
+    fn test_write_exr_file() {
+        // enough rows for two zip blocks, with values beyond 8 bits
+        let (width, height) = (5, 20);
+        let pixels: Vec<Vec<Color>> = (0..height).map(|y| (0..width).map(|x| Color {
+            r: x as f32 * 10.5,
+            g: y as f32 / 7.0,
+            b: 0.001 * (x * y) as f32
+        }).collect()).collect();
+        let path = std::env::temp_dir().join(format!("rs-tracer-test-{}.exr", std::process::id()));
+        let mut sizes = vec![];
+        for &(compression, code) in &[(ExrCompression::None, 0), (ExrCompression::Zip, 3)] {
+            let format = ImageFormat::from_name(if code == 0 { "exr-uncompressed" } else { "exr" }).unwrap();
+            assert_eq!(ImageFormat::Exr(compression), format);
+            write_image(&mut File::create(&path).unwrap(), format, &DisplayTransform::default(), &pixels).unwrap();
+            let data = fs::read(&path).unwrap();
+            assert_eq!((code, pixels.clone()), read_exr(&data, width, height));
+            sizes.push(data.len());
+        }
+        assert!(sizes[1] < sizes[0], "the zipped blocks should be smaller");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Some(ImageFormat::Exr(ExrCompression::Zip)), ImageFormat::from_extension(&path));
+    }
+}
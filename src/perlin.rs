@@ -1,54 +1,281 @@
 use arr_macro::arr;
 
-use rand::{prelude as random, Rng};
+use rand::Rng;
 use rand::seq::SliceRandom;
 
-use crate::{v3color::*, texture::*};
+use crate::{v3color::*, texture::*, rng::*};
 
-fn perlin_generate() -> [f32; 256] {
-    let mut rng = random::thread_rng();
-    arr!(rng.gen::<f32>(); 256)
+use std::f32::consts::PI;
+
+/// uniformly distributed over the sphere
+fn random_unit_vector(rng: &mut Pcg32) -> V3 {
+    let z = 1.0 - 2.0*rng.gen::<f32>();
+    let r = f32::sqrt(f32::max(0.0, 1.0 - z*z));
+    let phi = 2.0*PI*rng.gen::<f32>();
+    V3 { x: r*phi.cos(), y: r*phi.sin(), z }
 }
 
-fn perlin_generate_perm() -> [i32; 256] {
-    let mut rng = random::thread_rng();
+fn perlin_generate_perm(rng: &mut Pcg32) -> [i32; 256] {
     let mut i = 0;
     let mut p = arr!(({i+=1; i-1}); 256);
-    p.shuffle(&mut rng);
+    p.shuffle(rng);
     p
 }
 
-pub struct NoiseTexture {
-    pub ranfloat: [f32; 256],
+/// 3t² - 2t³: flat at both ends, so the cells join smoothly
+fn hermite(t: f32) -> f32 {
+    t*t*(3.0 - 2.0*t)
+}
+
+/// gradient noise: each lattice point gets a random direction,
+/// the noise is 0 there and rises along that direction
+pub struct Perlin {
+    pub gradients: [V3; 256],
     pub perm_x: [i32; 256],
     pub perm_y: [i32; 256],
     pub perm_z: [i32; 256]
 }
 
-impl NoiseTexture {
-    pub fn new() -> NoiseTexture {
-        let ranfloat = perlin_generate();
-        let perm_x = perlin_generate_perm();
-        let perm_y = perlin_generate_perm();
-        let perm_z = perlin_generate_perm();
-        NoiseTexture {
-            ranfloat, perm_x, perm_y, perm_z
+impl Perlin {
+    pub fn new(rng: &mut Pcg32) -> Perlin {
+        let gradients = arr!(random_unit_vector(rng); 256);
+        let perm_x = perlin_generate_perm(rng);
+        let perm_y = perlin_generate_perm(rng);
+        let perm_z = perlin_generate_perm(rng);
+        Perlin {
+            gradients, perm_x, perm_y, perm_z
+        }
+    }
+
+    /// roughly in -1..1, with features about one unit apart
+    pub fn noise(&self, p: &V3) -> f32 {
+        // floor rather than a cast, which would round negative coordinates up
+        let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (u, v, w) = (p.x - x0, p.y - y0, p.z - z0);
+        // wrapped before adding the neighbours, so that far away points
+        // don't overflow. & 255 wraps negative cells too.
+        let cell = |c: f32| (c as i64 & 255) as usize;
+        let (i, j, k) = (cell(x0), cell(y0), cell(z0));
+        let (uu, vv, ww) = (hermite(u), hermite(v), hermite(w));
+        let mut sum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let gradient = &self.gradients[(self.perm_x[(i + di) & 255]
+                        ^ self.perm_y[(j + dj) & 255]
+                        ^ self.perm_z[(k + dk) & 255]) as usize];
+                    let offset = V3 { x: u - di as f32, y: v - dj as f32, z: w - dk as f32 };
+                    let weight = (if di == 1 { uu } else { 1.0 - uu })
+                        * (if dj == 1 { vv } else { 1.0 - vv })
+                        * (if dk == 1 { ww } else { 1.0 - ww });
+                    sum += weight * V3::dot(gradient, &offset);
+                }
+            }
+        }
+        sum
+    }
+
+    /// the absolute value of the noise, plus `octaves - 1` more
+    /// octaves each twice as fine and half as strong. Not negative.
+    pub fn turbulence(&self, p: &V3, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut p = *p;
+        let mut weight = 1.0;
+        for _ in 0..octaves {
+            sum += weight * self.noise(&p).abs();
+            weight *= 0.5;
+            p = 2.0 * p;
         }
+        sum
     }
 
-    fn noise(&self, p: &V3) -> f32 {
-        // let u = p.x - f32::floor(p.x);
-        // let v = p.y - f32::floor(p.y);
-        // let w = p.z - f32::floor(p.z);
-        let i = ((4.0*p.x) as usize) & 255;
-        let j = ((4.0*p.y) as usize) & 255;
-        let k = ((4.0*p.z) as usize) & 255;
-        self.ranfloat[(self.perm_x[i] ^ self.perm_y[j] ^ self.perm_z[k]) as usize]
+    /// fractional Brownian motion: octaves each twice as fine as the
+    /// previous one and `gain` times as strong, summed with their sign.
+    /// Scaled back to roughly -1..1.
+    pub fn fbm(&self, p: &V3, octaves: u32, gain: f32) -> f32 {
+        let (mut sum, mut total_weight) = (0.0, 0.0);
+        let mut p = *p;
+        let mut weight = 1.0;
+        for _ in 0..octaves {
+            sum += weight * self.noise(&p);
+            total_weight += weight;
+            weight *= gain;
+            p = 2.0 * p;
+        }
+        sum / total_weight
     }
 }
 
+/// gray levels from Perlin noise
+pub struct NoiseTexture {
+    pub perlin: Perlin,
+    /// how many noise features per unit of distance
+    pub frequency: f32,
+    /// 1 for smooth noise, more for finer detail
+    pub octaves: u32
+}
+
 impl Texture for NoiseTexture {
-    fn value(&self, p: &V3) -> Color {
-        (V3 { x: 1.0, y: 1.0, z: 1.0} * self.noise(&p)).to_color()
+    fn value(&self, _u: f32, _v: f32, p: &V3) -> Color {
+        // fBm, so that the levels stay around 0.5 whatever the octaves
+        gray(0.5 * (1.0 + self.perlin.fbm(&(self.frequency * p), self.octaves, 0.5)))
+    }
+}
+
+/// veins: stripes across z, bent by turbulence. Gray, to go through a ramp.
+pub struct MarbleTexture {
+    pub perlin: Perlin,
+    /// the stripes are 2π/frequency apart
+    pub frequency: f32,
+    /// how much the stripes get bent
+    pub turbulence: f32,
+    pub octaves: u32
+}
+
+impl Texture for MarbleTexture {
+    fn value(&self, _u: f32, _v: f32, p: &V3) -> Color {
+        let p = self.frequency * p;
+        gray(0.5 * (1.0 + f32::sin(p.z + self.turbulence * self.perlin.turbulence(&p, self.octaves))))
+    }
+}
+
+/// growth rings around the y axis, made uneven by noise.
+/// Goes from 0 to 1 across each ring.
+pub struct WoodTexture {
+    pub perlin: Perlin,
+    /// rings per unit of distance
+    pub frequency: f32,
+    /// how far the rings wander, in rings
+    pub turbulence: f32,
+    pub octaves: u32
+}
+
+impl Texture for WoodTexture {
+    fn value(&self, _u: f32, _v: f32, p: &V3) -> Color {
+        let p = self.frequency * p;
+        let rings = f32::hypot(p.x, p.z) + self.turbulence * self.perlin.fbm(&p, self.octaves, 0.5);
+        gray(rings - rings.floor())
     }
-}
\ No newline at end of file
+}
+
+/// fBm around 0.5
+pub struct CloudsTexture {
+    pub perlin: Perlin,
+    pub frequency: f32,
+    pub octaves: u32,
+    /// the strength of each octave relative to the previous one,
+    /// higher is rougher
+    pub gain: f32
+}
+
+impl Texture for CloudsTexture {
+    fn value(&self, _u: f32, _v: f32, p: &V3) -> Color {
+        gray(0.5 * (1.0 + self.perlin.fbm(&(self.frequency * p), self.octaves, self.gain)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_noise() {
+        let perlin = Perlin::new(&mut Pcg32::new(0, 0));
+        let v3 = |x, y, z| V3 { x, y, z };
+        for &lattice_point in &[v3(0.0, 0.0, 0.0), v3(3.0, -2.0, 7.0), v3(-300.0, 1.0, -1.0)] {
+            assert_eq!(0.0, perlin.noise(&lattice_point));
+        }
+        // continuous across cells, negative ones included
+        for &p in &[v3(-1.0, 0.3, 0.6), v3(0.5, -2.0, 0.1), v3(0.25, -0.5, -3.0)] {
+            let e = 1e-3;
+            let before = perlin.noise(&(p - v3(e, e, e)));
+            let after = perlin.noise(&(p + v3(e, e, e)));
+            assert!((before - after).abs() < 1e-2, "{:?}: {} {}", p, before, after);
+        }
+        // not the same on both sides of 0, as with a cast towards 0
+        assert_ne!(perlin.noise(&v3(0.5, 0.5, 0.5)), perlin.noise(&v3(-0.5, 0.5, 0.5)));
+        let mut rng = Pcg32::new(1, 0);
+        let (mut min, mut max) = (0.0f32, 0.0f32);
+        for _ in 0..10_000 {
+            let p = 20.0 * random_unit_vector(&mut rng) * rng.gen::<f32>();
+            let n = perlin.noise(&p);
+            min = min.min(n);
+            max = max.max(n);
+            assert!(perlin.turbulence(&p, 5) >= 0.0);
+        }
+        assert!(min > -1.0 && min < -0.3 && max < 1.0 && max > 0.3, "{} {}", min, max);
+        // far away, where the cells can't be counted in an i32
+        for &x in &[1e10, -1e10, 3e9 + 0.5, f32::MAX, f32::MIN] {
+            let n = perlin.noise(&v3(x, 0.5, 0.5));
+            assert!(n.is_finite() && n.abs() < 1.0, "{}: {}", x, n);
+        }
+    }
+
+    #[test]
+    fn test_noise_texture_levels() {
+        // the same range whatever the octaves, smooth noise unchanged
+        let texture = |octaves| NoiseTexture { perlin: Perlin::new(&mut Pcg32::new(0, 0)), frequency: 1.0, octaves };
+        let (smooth, detailed) = (texture(1), texture(4));
+        let mut rng = Pcg32::new(2, 0);
+        let (mut sum, mut detailed_sum) = (0.0, 0.0);
+        for _ in 0..1_000 {
+            let p = 10.0 * random_unit_vector(&mut rng) * rng.gen::<f32>();
+            let level = smooth.value(0.0, 0.0, &p).r;
+            assert_eq!(0.5 * (1.0 + smooth.perlin.noise(&p)), level);
+            let detailed_level = detailed.value(0.0, 0.0, &p).r;
+            assert!((0.0..=1.0).contains(&detailed_level));
+            sum += level;
+            detailed_sum += detailed_level;
+        }
+        assert!((sum / 1000.0 - 0.5f32).abs() < 0.1 && (detailed_sum / 1000.0 - 0.5f32).abs() < 0.1,
+                "{} {}", sum / 1000.0, detailed_sum / 1000.0);
+    }
+
+    #[test]
+    fn test_fbm() {
+        let perlin = Perlin::new(&mut Pcg32::new(0, 0));
+        let again = Perlin::new(&mut Pcg32::new(0, 0));
+        let mut rng = Pcg32::new(3, 0);
+        let (mut min, mut max) = (0.0f32, 0.0f32);
+        for _ in 0..10_000 {
+            let p = 20.0 * random_unit_vector(&mut rng) * rng.gen::<f32>();
+            let n = perlin.fbm(&p, 6, 0.5);
+            assert_eq!(n, again.fbm(&p, 6, 0.5));
+            assert_eq!(perlin.noise(&p), perlin.fbm(&p, 1, 0.5));
+            min = min.min(n);
+            max = max.max(n);
+        }
+        assert!(min > -1.0 && min < -0.2 && max < 1.0 && max > 0.2, "{} {}", min, max);
+    }
+
+    #[test]
+    fn test_patterns() {
+        let perlin = || Perlin::new(&mut Pcg32::new(0, 0));
+        let marble = MarbleTexture { perlin: perlin(), frequency: 4.0, turbulence: 5.0, octaves: 6 };
+        let wood = WoodTexture { perlin: perlin(), frequency: 6.0, turbulence: 0.5, octaves: 6 };
+        let clouds = CloudsTexture { perlin: perlin(), frequency: 1.0, octaves: 6, gain: 0.5 };
+        let patterns: [&dyn Texture; 3] = [&marble, &wood, &clouds];
+        let same_patterns: [Box<dyn Texture>; 3] = [
+            Box::new(MarbleTexture { perlin: perlin(), ..marble }),
+            Box::new(WoodTexture { perlin: perlin(), ..wood }),
+            Box::new(CloudsTexture { perlin: perlin(), ..clouds })
+        ];
+        let mut rng = Pcg32::new(4, 0);
+        // clouds stay closer to 0.5
+        let spreads = [0.5, 0.5, 0.2];
+        for ((pattern, same), spread) in patterns.iter().zip(same_patterns.iter()).zip(spreads.iter()) {
+            let (mut min, mut max) = (1.0f32, 0.0f32);
+            for _ in 0..2_000 {
+                let p = 5.0 * random_unit_vector(&mut rng) * rng.gen::<f32>();
+                let c = pattern.value(0.0, 0.0, &p);
+                // gray levels in 0..1, the same for the same seed
+                assert!(c.r == c.g && c.g == c.b && (0.0..=1.0).contains(&c.r));
+                assert_eq!(c, same.value(0.0, 0.0, &p));
+                min = min.min(c.r);
+                max = max.max(c.r);
+            }
+            // and they vary
+            assert!(max - min > *spread, "{} {}", min, max);
+        }
+    }
+}
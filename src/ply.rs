@@ -0,0 +1,446 @@
+// PLY polygon files, ascii or binary little-endian
+// http://paulbourke.net/dataformats/ply/
+
+use crate::{v3color::*, shapes::*, material::*, texture::*, mesh::*};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+/// when `material` is None, the per-vertex colors of the file are used if
+/// there are some, otherwise the default mesh material.
+pub fn load_ply(path: &Path, material: Option<Arc<dyn Material>>) -> Result<Mesh, LoadError> {
+    parse_ply(BufReader::new(File::open(path)?), material)
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ScalarType {
+    Int8, UInt8, Int16, UInt16, Int32, UInt32, Float32, Float64
+}
+
+impl ScalarType {
+    fn parse(line_nr: usize, name: &str) -> Result<ScalarType, LoadError> {
+        match name {
+            "char" | "int8" => Ok(ScalarType::Int8),
+            "uchar" | "uint8" => Ok(ScalarType::UInt8),
+            "short" | "int16" => Ok(ScalarType::Int16),
+            "ushort" | "uint16" => Ok(ScalarType::UInt16),
+            "int" | "int32" => Ok(ScalarType::Int32),
+            "uint" | "uint32" => Ok(ScalarType::UInt32),
+            "float" | "float32" => Ok(ScalarType::Float32),
+            "double" | "float64" => Ok(ScalarType::Float64),
+            _ => parse_error(line_nr, format!("unknown property type: {}", name))
+        }
+    }
+}
+
+enum PropertyKind {
+    Scalar(ScalarType),
+    List { count: ScalarType, item: ScalarType }
+}
+
+struct Property {
+    name: String,
+    kind: PropertyKind
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>
+}
+
+impl Element {
+    fn property_index(&self, names: &[&str]) -> Option<usize> {
+        self.properties.iter().position(|p| names.contains(&p.name.as_str()))
+    }
+}
+
+#[derive(PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian
+}
+
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+    /// number of lines, so that ascii body errors have the right line number
+    line_count: usize
+}
+
+fn parse_header<R: BufRead>(reader: &mut R) -> Result<Header, LoadError> {
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    let mut line_nr = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return parse_error(line_nr, "missing end_header".to_string());
+        }
+        line_nr += 1;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["ply"] if line_nr == 1 => {},
+            _ if line_nr == 1 => return parse_error(line_nr, "not a PLY file".to_string()),
+            ["format", "ascii", _] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(Format::BinaryLittleEndian),
+            ["format", f, _] => return parse_error(line_nr, format!("unsupported format: {}", f)),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().or_else(
+                    |_| parse_error(line_nr, format!("invalid element count: {}", count)))?,
+                properties: vec![]
+            }),
+            ["property", "list", count, item, name] => {
+                let kind = PropertyKind::List {
+                    count: ScalarType::parse(line_nr, count)?,
+                    item: ScalarType::parse(line_nr, item)?
+                };
+                match elements.last_mut() {
+                    Some(e) => e.properties.push(Property { name: name.to_string(), kind }),
+                    None => return parse_error(line_nr, "property before any element".to_string())
+                }
+            },
+            ["property", ty, name] => {
+                let kind = PropertyKind::Scalar(ScalarType::parse(line_nr, ty)?);
+                match elements.last_mut() {
+                    Some(e) => e.properties.push(Property { name: name.to_string(), kind }),
+                    None => return parse_error(line_nr, "property before any element".to_string())
+                }
+            },
+            ["comment", ..] | ["obj_info", ..] | [] => {},
+            ["end_header"] => break,
+            _ => return parse_error(line_nr, format!("unexpected header line: {}", line.trim()))
+        }
+    }
+    match format {
+        Some(format) => Ok(Header { format, elements, line_count: line_nr }),
+        None => parse_error(line_nr, "missing format".to_string())
+    }
+}
+
+/// reads the values of the body one by one, whatever the encoding
+trait BodyReader {
+    fn read(&mut self, ty: ScalarType) -> Result<f64, LoadError>;
+}
+
+struct AsciiBody<R> {
+    reader: R,
+    line_nr: usize,
+    tokens: Vec<String>
+}
+
+impl<R: BufRead> BodyReader for AsciiBody<R> {
+    fn read(&mut self, _ty: ScalarType) -> Result<f64, LoadError> {
+        while self.tokens.is_empty() {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return parse_error(self.line_nr, "unexpected end of file".to_string());
+            }
+            self.line_nr += 1;
+            self.tokens = line.split_whitespace().rev().map(|t| t.to_string()).collect();
+        }
+        let token = self.tokens.pop().unwrap();
+        token.parse().or_else(|_| parse_error(self.line_nr, format!("invalid number: {}", token)))
+    }
+}
+
+struct BinaryLittleEndianBody<R> {
+    reader: R
+}
+
+impl<R: Read> BinaryLittleEndianBody<R> {
+    fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        let mut buf = [0; N];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl<R: Read> BodyReader for BinaryLittleEndianBody<R> {
+    fn read(&mut self, ty: ScalarType) -> Result<f64, LoadError> {
+        Ok(match ty {
+            ScalarType::Int8 => i8::from_le_bytes(self.read_bytes()?) as f64,
+            ScalarType::UInt8 => u8::from_le_bytes(self.read_bytes()?) as f64,
+            ScalarType::Int16 => i16::from_le_bytes(self.read_bytes()?) as f64,
+            ScalarType::UInt16 => u16::from_le_bytes(self.read_bytes()?) as f64,
+            ScalarType::Int32 => i32::from_le_bytes(self.read_bytes()?) as f64,
+            ScalarType::UInt32 => u32::from_le_bytes(self.read_bytes()?) as f64,
+            ScalarType::Float32 => f32::from_le_bytes(self.read_bytes()?) as f64,
+            ScalarType::Float64 => f64::from_le_bytes(self.read_bytes()?)
+        })
+    }
+}
+
+/// all the values of one element instance. Lists are
+/// flattened, `ranges` gives the span of each property.
+struct ElementValues {
+    values: Vec<f64>,
+    ranges: Vec<std::ops::Range<usize>>
+}
+
+impl ElementValues {
+    fn get(&self, property: usize) -> &[f64] {
+        &self.values[self.ranges[property].clone()]
+    }
+}
+
+fn read_element(body: &mut dyn BodyReader, element: &Element, values: &mut ElementValues) -> Result<(), LoadError> {
+    values.values.clear();
+    values.ranges.clear();
+    for property in &element.properties {
+        let start = values.values.len();
+        match property.kind {
+            PropertyKind::Scalar(ty) => values.values.push(body.read(ty)?),
+            PropertyKind::List { count, item } => {
+                let n = body.read(count)? as usize;
+                for _ in 0..n {
+                    values.values.push(body.read(item)?);
+                }
+            }
+        }
+        values.ranges.push(start..values.values.len());
+    }
+    Ok(())
+}
+
+struct Vertex {
+    position: V3,
+    normal: Option<V3>,
+    color: Option<Color>,
+    uv: Option<(f32, f32)>
+}
+
+fn parse_ply<R: BufRead>(mut reader: R, material: Option<Arc<dyn Material>>) -> Result<Mesh, LoadError> {
+    let header = parse_header(&mut reader)?;
+    let mut body: Box<dyn BodyReader> = match header.format {
+        Format::Ascii => Box::new(AsciiBody { reader, line_nr: header.line_count, tokens: vec![] }),
+        Format::BinaryLittleEndian => Box::new(BinaryLittleEndianBody { reader })
+    };
+    let mut vertices: Vec<Vertex> = vec![];
+    let mut triangles = vec![];
+    let default_material = material.clone().unwrap_or_else(default_mesh_material);
+    // one material per distinct face color, most colored meshes only use a few
+    let mut color_materials: HashMap<[u32; 3], Arc<dyn Material>> = HashMap::new();
+    let mut values = ElementValues { values: vec![], ranges: vec![] };
+    for element in &header.elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let coords = |names: [&str; 3]| -> Option<[usize; 3]> {
+                    Some([element.property_index(&[names[0]])?,
+                          element.property_index(&[names[1]])?,
+                          element.property_index(&[names[2]])?])
+                };
+                let position = coords(["x", "y", "z"]).ok_or_else(|| LoadError::Parse {
+                    line: header.line_count,
+                    message: "vertices have no x, y, z properties".to_string()
+                })?;
+                let normal = coords(["nx", "ny", "nz"]);
+                let color = coords(["red", "green", "blue"])
+                    .or_else(|| coords(["r", "g", "b"]))
+                    .or_else(|| coords(["diffuse_red", "diffuse_green", "diffuse_blue"]));
+                let color_is_float = color.is_some_and(|c| matches!(
+                    element.properties[c[0]].kind,
+                    PropertyKind::Scalar(ScalarType::Float32) | PropertyKind::Scalar(ScalarType::Float64)));
+                let uv = element.property_index(&["u", "s", "texture_u", "texture_s"])
+                    .zip(element.property_index(&["v", "t", "texture_v", "texture_t"]));
+                for _ in 0..element.count {
+                    read_element(&mut *body, element, &mut values)?;
+                    let scalar = |i: usize| values.get(i).first().copied().unwrap_or(0.0) as f32;
+                    let v3 = |c: [usize; 3]| V3 { x: scalar(c[0]), y: scalar(c[1]), z: scalar(c[2]) };
+                    let color_component = |i| if color_is_float {
+                        scalar(i)
+                    } else {
+                        srgb_to_linear(scalar(i) / 255.0)
+                    };
+                    vertices.push(Vertex {
+                        position: v3(position),
+                        normal: normal.map(|n| v3(n).unit()),
+                        color: color.map(|c| Color {
+                            r: color_component(c[0]),
+                            g: color_component(c[1]),
+                            b: color_component(c[2])
+                        }),
+                        uv: uv.map(|(u, v)| (scalar(u), scalar(v)))
+                    });
+                }
+            },
+            "face" => {
+                let indices = element.property_index(&["vertex_indices", "vertex_index"]).ok_or_else(|| LoadError::Parse {
+                    line: header.line_count,
+                    message: "faces have no vertex_indices property".to_string()
+                })?;
+                for face_nr in 0..element.count {
+                    read_element(&mut *body, element, &mut values)?;
+                    let face = values.get(indices).iter()
+                        .map(|&i| {
+                            // the list is read as floats, which must not wrap
+                            // around when cast to an index
+                            if i < 0.0 || i.fract() != 0.0 {
+                                return parse_error(header.line_count,
+                                                   format!("face {}: invalid vertex index {}", face_nr, i));
+                            }
+                            vertices.get(i as usize).ok_or_else(|| LoadError::Parse {
+                                line: header.line_count,
+                                message: format!("face {}: vertex index {} out of range", face_nr, i)
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    // polygons are split in a fan around their first vertex
+                    for k in 1..face.len().saturating_sub(1) {
+                        let vs = [face[0], face[k], face[k+1]];
+                        let face_material = match (&material, vs[0].color, vs[1].color, vs[2].color) {
+                            (None, Some(c0), Some(c1), Some(c2)) => {
+                                let color = ((c0.to_v3() + c1.to_v3() + c2.to_v3()) / 3.0).to_color();
+                                color_materials.entry([color.r.to_bits(), color.g.to_bits(), color.b.to_bits()])
+                                    .or_insert_with(|| Arc::new(Lambertian {
+                                        albedo: Box::new(ConstantTexture { color })
+                                    }))
+                                    .clone()
+                            },
+                            _ => default_material.clone()
+                        };
+                        triangles.push(Triangle {
+                            vertices: [vs[0].position, vs[1].position, vs[2].position],
+                            normals: match (vs[0].normal, vs[1].normal, vs[2].normal) {
+                                (Some(n0), Some(n1), Some(n2)) => Some([n0, n1, n2]),
+                                _ => None
+                            },
+                            uvs: match (vs[0].uv, vs[1].uv, vs[2].uv) {
+                                (Some(t0), Some(t1), Some(t2)) => Some([t0, t1, t2]),
+                                _ => None
+                            },
+                            material: face_material
+                        });
+                    }
+                }
+            },
+            // other elements (edges, materials...) are read and dropped,
+            // in binary files we have to get past them anyway.
+            _ => for _ in 0..element.count {
+                read_element(&mut *body, element, &mut values)?;
+            }
+        }
+    }
+    Mesh::new(triangles)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sampler::*;
+
+    fn down_ray() -> Ray {
+        Ray {
+            origin: V3 {x: 0.25, y: 0.25, z: 1.0},
+            direction: V3 {x: 0.0, y: 0.0, z: -1.0},
+            time: 0.0
+        }
+    }
+
+    #[test]
+    fn test_parse_ascii() {
+        let mesh = parse_ply("ply
+format ascii 1.0
+comment a unit quad in the XY plane
+element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 255 0 0
+1 0 0 255 0 0
+1 1 0 255 0 0
+0 1 0 255 0 0
+4 0 1 2 3
+".as_bytes(), None).unwrap();
+        assert_eq!(2, mesh.triangle_count());
+        let ray = down_ray();
+        let hit = mesh.hit(&ray, &(0.001..f32::MAX)).expect("should hit the quad");
+        assert_eq!(1.0, hit.t);
+        let scattered = hit.material.scatter(&ray, &hit, &mut IndependentSampler::new(0)).unwrap();
+        assert_eq!(Color { r: 1.0, g: 0.0, b: 0.0 }, scattered.attenuation);
+        // both halves of the quad have the same color, and the same material
+        let half = |x, y| mesh.hit(&Ray { origin: V3 {x, y, z: 1.0}, ..down_ray() },
+                                   &(0.001..f32::MAX)).unwrap().material as *const dyn Material as *const u8;
+        assert!(std::ptr::eq(half(0.75, 0.25), half(0.25, 0.75)));
+    }
+
+    #[test]
+    fn test_parse_binary_little_endian() {
+        let mut data = b"ply
+format binary_little_endian 1.0
+element vertex 3
+property double x
+property double y
+property double z
+element face 1
+property list uchar uint vertex_indices
+end_header
+".to_vec();
+        for v in &[[0.0f64, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]] {
+            for c in v {
+                data.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+        data.push(3);
+        for i in 0..3u32 {
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+        let mesh = parse_ply(data.as_slice(), None).unwrap();
+        assert_eq!(1, mesh.triangle_count());
+        assert!(mesh.hit(&down_ray(), &(0.001..f32::MAX)).is_some());
+
+        // truncated body
+        data.pop();
+        assert!(parse_ply(data.as_slice(), None).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        match parse_ply("ply\nformat binary_big_endian 1.0\nend_header\n".as_bytes(), None) {
+            Err(LoadError::Parse { line: 2, .. }) => {},
+            _ => panic!("expected an error on line 2")
+        }
+        match parse_ply("ply
+format ascii 1.0
+element vertex 1
+property float x
+property float y
+property float z
+end_header
+0 0 zero
+".as_bytes(), None) {
+            Err(LoadError::Parse { line: 8, .. }) => {},
+            _ => panic!("expected an error on line 8")
+        }
+        for index in &["-1", "0.5"] {
+            let ply = format!("ply
+format ascii 1.0
+element vertex 3
+property float x
+property float y
+property float z
+element face 1
+property list uchar float vertex_indices
+end_header
+0 0 0
+1 0 0
+0 1 0
+3 0 1 {}
+", index);
+            match parse_ply(ply.as_bytes(), None) {
+                Err(LoadError::Parse { message, .. }) => assert!(message.contains("invalid vertex index")),
+                _ => panic!("expected an invalid index error for {}", index)
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// PNG encoding, 8-bit RGB only, and decoding of non-interlaced images
+
+use crate::deflate::*;
+
+use std::io::{self, Write};
+
+static SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const BYTES_PER_PIXEL: usize = 3;
+
+fn write_chunk(out: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc32_update(crc32(kind), data).to_be_bytes())
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+/// applies one of the five PNG filters to a row,
+/// `prev` being the unfiltered previous row (zeroes for the first one)
+fn filter_row(filter: u8, row: &[u8], prev: &[u8], out: &mut Vec<u8>) {
+    out.push(filter);
+    for i in 0..row.len() {
+        let a = if i >= BYTES_PER_PIXEL { row[i - BYTES_PER_PIXEL] } else { 0 };
+        let b = prev[i];
+        let c = if i >= BYTES_PER_PIXEL { prev[i - BYTES_PER_PIXEL] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth(a, b, c)
+        };
+        out.push(row[i].wrapping_sub(predicted));
+    }
+}
+
+/// picks the filter per row with the usual minimum sum of
+/// absolute differences heuristic, then compresses
+fn image_data(width: usize, rgb: &[u8]) -> Vec<u8> {
+    let stride = width * BYTES_PER_PIXEL;
+    let mut filtered = Vec::with_capacity(rgb.len() + rgb.len() / stride);
+    let zeroes = vec![0; stride];
+    let mut candidate = Vec::with_capacity(stride + 1);
+    let mut best = Vec::with_capacity(stride + 1);
+    for (y, row) in rgb.chunks(stride).enumerate() {
+        let prev = if y == 0 { &zeroes[..] } else { &rgb[(y - 1) * stride..y * stride] };
+        let mut best_score = u64::MAX;
+        for filter in 0..5 {
+            candidate.clear();
+            filter_row(filter, row, prev, &mut candidate);
+            let score = candidate[1..].iter().map(|&v| (v as i8).unsigned_abs() as u64).sum();
+            if score < best_score {
+                best_score = score;
+                std::mem::swap(&mut best, &mut candidate);
+            }
+        }
+        filtered.extend(&best);
+    }
+    zlib_compress(&filtered)
+}
+
+/// `rgb` has the rows top to bottom, three bytes per pixel
+pub fn write_png(out: &mut dyn Write, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    assert_eq!(width as usize * height as usize * BYTES_PER_PIXEL, rgb.len());
+    out.write_all(&SIGNATURE)?;
+    let mut header = Vec::with_capacity(13);
+    header.extend(&width.to_be_bytes());
+    header.extend(&height.to_be_bytes());
+    // bit depth 8, truecolor, deflate, adaptive filtering, no interlace
+    header.extend(&[8, 2, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+    // perceptual rendering intent
+    write_chunk(out, b"sRGB", &[0])?;
+    write_chunk(out, b"IDAT", &image_data(width as usize, rgb))?;
+    write_chunk(out, b"IEND", &[])?;
+    out.flush()
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+/// undoes `filter_row` in place, `bpp` being the bytes per pixel (at least 1)
+fn unfilter_row(filter: u8, row: &mut [u8], prev: &[u8], bpp: usize) -> Result<(), String> {
+    for i in 0..row.len() {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            4 => paeth(a, b, c),
+            _ => return Err(format!("invalid filter type {}", filter))
+        };
+        row[i] = row[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+/// returns the width, the height and the RGB values in 0..1, still
+/// sRGB encoded, rows top to bottom. Alpha is dropped.
+pub fn read_png(data: &[u8]) -> Result<(usize, usize, Vec<f32>), String> {
+    if data.len() < 8 || data[..8] != SIGNATURE {
+        return Err("not a PNG file".to_string());
+    }
+    let mut header = None;
+    let mut palette = Vec::new();
+    let mut compressed = Vec::new();
+    let mut pos = 8;
+    loop {
+        if pos + 12 > data.len() {
+            return Err("the file is truncated".to_string());
+        }
+        let length = read_u32(&data[pos..]) as usize;
+        let kind = &data[pos + 4..pos + 8];
+        let chunk = data.get(pos + 8..pos + 8 + length).ok_or("the file is truncated")?;
+        let crc = data.get(pos + 8 + length..pos + 12 + length).ok_or("the file is truncated")?;
+        if crc32_update(crc32(kind), chunk).to_be_bytes() != crc {
+            return Err(format!("bad checksum for the {} chunk", String::from_utf8_lossy(kind)));
+        }
+        pos += 12 + length;
+        match kind {
+            b"IHDR" if chunk.len() == 13 => header = Some(chunk),
+            b"PLTE" => palette = chunk.to_vec(),
+            b"IDAT" => compressed.extend(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or("the IHDR chunk is missing")?;
+    let (width, height) = (read_u32(header) as usize, read_u32(&header[4..]) as usize);
+    let (depth, color_type) = (header[8] as usize, header[9]);
+    if header[12] != 0 {
+        return Err("interlaced PNGs aren't supported".to_string());
+    }
+    let channels = match (color_type, depth) {
+        (0, 1) | (0, 2) | (0, 4) | (0, 8) | (0, 16) => 1,
+        (2, 8) | (2, 16) => 3,
+        (3, 1) | (3, 2) | (3, 4) | (3, 8) => 1,
+        (4, 8) | (4, 16) => 2,
+        (6, 8) | (6, 16) => 4,
+        _ => return Err(format!("unsupported color type {} with bit depth {}", color_type, depth))
+    };
+    if color_type == 3 && palette.is_empty() {
+        return Err("the PLTE chunk is missing".to_string());
+    }
+
+    // the header may be anything, the sizes must not overflow
+    let too_large = || format!("the image is too large: {}x{}", width, height);
+    let stride = width.checked_mul(channels * depth).ok_or_else(too_large)?.div_ceil(8);
+    let data_size = height.checked_mul(stride + 1).ok_or_else(too_large)?;
+    let bpp = (channels * depth / 8).max(1);
+    let mut filtered = zlib_decompress(&compressed)?;
+    if filtered.len() < data_size {
+        return Err("the image data is truncated".to_string());
+    }
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    let mut prev = vec![0; stride];
+    for line in filtered.chunks_mut(stride + 1).take(height) {
+        let (filter, row) = line.split_first_mut().unwrap();
+        unfilter_row(*filter, row, &prev, bpp)?;
+        let max = ((1 << depth) - 1) as f32;
+        let sample = |i: usize| -> usize {
+            match depth {
+                16 => (row[2*i] as usize) << 8 | row[2*i + 1] as usize,
+                8 => row[i] as usize,
+                // several samples in a byte, the leftmost in the high bits
+                _ => (row[i * depth / 8] as usize >> (8 - depth - i * depth % 8)) & ((1 << depth) - 1)
+            }
+        };
+        for x in 0..width {
+            match color_type {
+                0 | 4 => {
+                    let gray = sample(x * channels) as f32 / max;
+                    rgb.extend(&[gray, gray, gray]);
+                },
+                3 => {
+                    let entry = palette.get(3 * sample(x)..3 * sample(x) + 3)
+                        .ok_or("a palette index is out of range")?;
+                    rgb.extend(entry.iter().map(|&c| c as f32 / 255.0));
+                },
+                _ => rgb.extend((0..3).map(|c| sample(x * channels + c) as f32 / max))
+            }
+        }
+        prev.copy_from_slice(row);
+    }
+    Ok((width, height, rgb))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_png() {
+        let rgb: Vec<u8> = (0..4*3*3).map(|i| (i * 7) as u8).collect();
+        let mut png = Vec::new();
+        write_png(&mut png, 4, 3, &rgb).unwrap();
+        assert_eq!(&SIGNATURE, &png[..8]);
+        // IHDR length, type, width and height
+        assert_eq!(&[0, 0, 0, 13], &png[8..12]);
+        assert_eq!(b"IHDR", &png[12..16]);
+        assert_eq!(&[0, 0, 0, 4, 0, 0, 0, 3], &png[16..24]);
+        assert_eq!(crc32(&png[12..29]).to_be_bytes(), png[29..33]);
+        assert_eq!(b"IEND", &png[png.len()-8..png.len()-4]);
+    }
+
+    #[test]
+    fn test_read_png() {
+        let rgb: Vec<u8> = (0..17*5*3).map(|i| (i * i % 256) as u8).collect();
+        let mut png = Vec::new();
+        write_png(&mut png, 17, 5, &rgb).unwrap();
+        let (width, height, values) = read_png(&png).unwrap();
+        assert_eq!((17, 5), (width, height));
+        assert_eq!(rgb, values.iter().map(|&v| (v * 255.0).round() as u8).collect::<Vec<_>>());
+        *png.last_mut().unwrap() ^= 1;
+        assert!(read_png(&png).is_err());
+
+        // a header claiming more data than fits in memory
+        let mut png = SIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &[255, 255, 255, 255, 255, 255, 255, 255, 16, 6, 0, 0, 0]).unwrap();
+        write_chunk(&mut png, b"IDAT", &zlib_compress(&[0; 16])).unwrap();
+        write_chunk(&mut png, b"IEND", &[]).unwrap();
+        assert!(read_png(&png).unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn test_read_png_formats() {
+        let png = |header: [u8; 5], rows: &[u8], extra: &[(&[u8; 4], &[u8])]| {
+            let mut png = SIGNATURE.to_vec();
+            let mut ihdr = vec![0, 0, 0, 3, 0, 0, 0, 1];
+            ihdr.extend(&header);
+            write_chunk(&mut png, b"IHDR", &ihdr).unwrap();
+            for (kind, data) in extra {
+                write_chunk(&mut png, kind, data).unwrap();
+            }
+            write_chunk(&mut png, b"IDAT", &zlib_compress(rows)).unwrap();
+            write_chunk(&mut png, b"IEND", &[]).unwrap();
+            read_png(&png).unwrap().2
+        };
+        // 2-bit palette indices 2, 0, 1
+        assert_eq!(vec![0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+                   png([2, 3, 0, 0, 0], &[0, 0b10_00_01_00], &[(b"PLTE", &[255, 0, 0, 0, 255, 0, 0, 0, 255])]));
+        // 16-bit gray and alpha, with the sub filter
+        assert_eq!(vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0],
+                   png([16, 4, 0, 0, 0], &[1, 0, 0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0], &[]));
+    }
+}
@@ -0,0 +1,85 @@
+// reproducible pseudo-random numbers
+
+use rand::{RngCore, Error};
+
+const PCG_MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+/// the stream of the numbers used while building scenes,
+/// pixel samples use the default one
+pub const SCENE_STREAM: u64 = 1;
+
+/// splitmix64's finalizer: spreads structured values such as
+/// pixel coordinates over all the bits
+pub fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// a hash of the exact bits of the values
+pub fn hash_floats(values: &[f32]) -> u64 {
+    values.iter().fold(0, |h, v| mix64(h ^ v.to_bits() as u64))
+}
+
+/// PCG32 (XSH RR). Unlike `thread_rng()` the numbers only depend
+/// on the seed, and it's cheap enough to create one per sample.
+#[derive(Clone)]
+pub struct Pcg32 {
+    state: u64,
+    increment: u64
+}
+
+impl Pcg32 {
+    /// generators with different streams give unrelated sequences
+    pub fn new(seed: u64, stream: u64) -> Pcg32 {
+        let mut rng = Pcg32 { state: 0, increment: (stream << 1) | 1 };
+        rng.step();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.step();
+        rng
+    }
+
+    fn step(&mut self) {
+        self.state = self.state.wrapping_mul(PCG_MULTIPLIER).wrapping_add(self.increment);
+    }
+}
+
+impl RngCore for Pcg32 {
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.step();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pcg32() {
+        // from the reference implementation, pcg32-demo with seed 42 and stream 54
+        let mut rng = Pcg32::new(42, 54);
+        let expected = [0xa15c_02b7, 0x7b47_f409, 0xba1d_3330, 0x83d2_f293, 0xbfa4_784b, 0xcbed_606e];
+        for &e in &expected {
+            assert_eq!(e, rng.next_u32());
+        }
+    }
+}
@@ -0,0 +1,433 @@
+// sample points for the pixel, lens, time and scattering dimensions
+
+use crate::rng::*;
+
+use rand::Rng;
+
+/// hands out the successive dimensions of one sample of a pixel,
+/// each number in [0, 1). Samplers other than the independent one
+/// spread the samples of a pixel evenly over each dimension.
+pub trait Sampler: Send {
+    /// back to the first dimension, for sample `index` of pixel (x, y)
+    fn start_sample(&mut self, x: u32, y: u32, index: u32);
+
+    fn get_1d(&mut self) -> f32;
+
+    /// two dimensions which are well distributed together,
+    /// for the pixel position or the lens for instance
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol
+}
+
+impl SamplerKind {
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name {
+            "independent" => Some(SamplerKind::Independent),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            _ => None
+        }
+    }
+
+    /// `samples_per_pixel` is only needed by the stratified sampler,
+    /// the others can go on indefinitely
+    pub fn sampler(self, seed: u64, samples_per_pixel: u32) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed))
+        }
+    }
+
+    /// how many successive samples of a pixel, starting at a multiple of
+    /// it, are spread evenly on their own, so that the samples can stop
+    /// after any number of these batches. None for the stratified sampler,
+    /// which only spreads all the samples per pixel together
+    pub fn batch_size(self) -> Option<u32> {
+        match self {
+            SamplerKind::Independent => Some(1),
+            SamplerKind::Stratified => None,
+            // one in each half and third of the first two dimensions
+            SamplerKind::Halton => Some(6),
+            // any power of two would do
+            SamplerKind::Sobol => Some(16)
+        }
+    }
+}
+
+/// the largest f32 below 1
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+/// the 24 high bits, as many as a f32 can hold
+fn u32_to_unit_f32(x: u32) -> f32 {
+    (x >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+fn pixel_hash(seed: u64, x: u32, y: u32) -> u64 {
+    mix64(seed ^ mix64((y as u64) << 32 | x as u64))
+}
+
+/// a uniform random number for every dimension
+pub struct IndependentSampler {
+    seed: u64,
+    rng: Pcg32
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> IndependentSampler {
+        IndependentSampler { seed, rng: Pcg32::new(seed, 0) }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.rng = Pcg32::new(mix64(pixel_hash(self.seed, x, y) ^ index as u64), 0);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.gen::<f32>()
+    }
+}
+
+/// Andrew Kensler's hash-based permutation of 0..length,
+/// from "Correlated Multi-Jittered Sampling"
+fn permute(mut i: u32, length: u32, p: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    // cycle walking, until we land within the length
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % length
+}
+
+/// jittered samples: for n samples each dimension gets split in n strata,
+/// the samples of a pixel falling in distinct strata. The 2D samples
+/// are correlated multi-jittered, stratified in both dimensions as well.
+pub struct StratifiedSampler {
+    seed: u64,
+    samples_per_pixel: u32,
+    pixel: u32,
+    index: u32,
+    dimension: u32
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> StratifiedSampler {
+        StratifiedSampler { seed, samples_per_pixel, pixel: 0, index: 0, dimension: 0 }
+    }
+
+    /// the seed of the permutations for the current pixel and dimension
+    fn pattern(&mut self) -> u32 {
+        self.dimension += 1;
+        mix64((self.pixel as u64) << 32 | self.dimension as u64) as u32
+    }
+
+    fn jitter(&self, p: u32) -> f32 {
+        u32_to_unit_f32(mix64((p as u64) << 32 | self.index as u64) as u32)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = pixel_hash(self.seed, x, y) as u32;
+        // past the expected count, samples start a new set of strata
+        self.index = index % self.samples_per_pixel;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let p = self.pattern();
+        let n = self.samples_per_pixel;
+        let stratum = permute(self.index, n, p);
+        ((stratum as f32 + self.jitter(p)) / n as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        // n samples in a m x k grid, each row and column
+        // of the n x n fine grid getting one sample
+        let p = self.pattern();
+        let n = self.samples_per_pixel;
+        let m = (n as f32).sqrt() as u32;
+        let k = n.div_ceil(m);
+        let s = permute(self.index, n, p.wrapping_mul(0x5163_3e2d));
+        let sx = permute(s % m, m, p.wrapping_mul(0x68bc_21eb));
+        let sy = permute(s / m, k, p.wrapping_mul(0x02e5_be93));
+        let jx = self.jitter(p.wrapping_mul(0x967a_889b));
+        let jy = self.jitter(p.wrapping_mul(0x368c_c8b7));
+        let x = (sx as f32 + (sy as f32 + jx) / k as f32) / m as f32;
+        let y = (s as f32 + jy) / n as f32;
+        (x.min(ONE_MINUS_EPSILON), y.min(ONE_MINUS_EPSILON))
+    }
+}
+
+/// bases of the Halton dimensions. Beyond these the
+/// samples are independent, large bases correlate badly.
+static PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131
+];
+
+/// the digits of `i` in `base`, mirrored around the decimal point. Each
+/// digit goes through a permutation depending on the previous ones, which
+/// is Owen scrambling: it keeps the stratification but breaks the
+/// correlation between the dimensions with large bases.
+fn scrambled_radical_inverse(base: u32, mut i: u32, hash: u64) -> f32 {
+    let inverse_base = 1.0 / base as f32;
+    let mut factor = 1.0;
+    let mut reversed_digits: u64 = 0;
+    // the digits past the ones of i are zeroes, but scrambled they aren't
+    while 1.0 - factor < 1.0 {
+        let digit = i % base;
+        let digit_hash = mix64(hash ^ reversed_digits) as u32;
+        reversed_digits = reversed_digits * base as u64 + permute(digit, base, digit_hash) as u64;
+        factor *= inverse_base;
+        i /= base;
+    }
+    (factor * reversed_digits as f32).min(ONE_MINUS_EPSILON)
+}
+
+/// the Halton sequence, Owen scrambled per pixel
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: usize
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler { seed, pixel: 0, index: 0, dimension: 0 }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = pixel_hash(self.seed, x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let hash = mix64(self.pixel ^ self.dimension as u64);
+        let value = match PRIMES.get(self.dimension) {
+            Some(&base) => scrambled_radical_inverse(base, self.index, hash),
+            None => u32_to_unit_f32(mix64(hash ^ self.index as u64) as u32)
+        };
+        self.dimension += 1;
+        value.min(ONE_MINUS_EPSILON)
+    }
+}
+
+/// direction numbers for the first four Sobol dimensions, from
+/// the primitive polynomials and initial numbers of Joe and Kuo
+const fn sobol_directions() -> [[u32; 32]; 4] {
+    // degree, coefficients and initial direction numbers.
+    // The first dimension is the van der Corput sequence.
+    let polynomials: [(usize, u32, [u32; 3]); 3] = [(1, 0, [1, 0, 0]), (2, 1, [1, 3, 0]), (3, 1, [1, 3, 1])];
+    let mut directions = [[0; 32]; 4];
+    let mut bit = 0;
+    while bit < 32 {
+        directions[0][bit] = 1 << (31 - bit);
+        bit += 1;
+    }
+    let mut d = 0;
+    while d < 3 {
+        let (degree, a, m) = polynomials[d];
+        let v = &mut directions[d + 1];
+        let mut k = 0;
+        while k < 32 {
+            if k < degree {
+                v[k] = m[k] << (31 - k);
+            } else {
+                v[k] = v[k - degree] ^ (v[k - degree] >> degree);
+                let mut j = 1;
+                while j < degree {
+                    if (a >> (degree - 1 - j)) & 1 == 1 {
+                        v[k] ^= v[k - j];
+                    }
+                    j += 1;
+                }
+            }
+            k += 1;
+        }
+        d += 1;
+    }
+    directions
+}
+
+static SOBOL_DIRECTIONS: [[u32; 32]; 4] = sobol_directions();
+
+fn sobol(index: u32, dimension: usize) -> u32 {
+    (0..32).filter(|bit| (index >> bit) & 1 == 1)
+        .fold(0, |x, bit| x ^ SOBOL_DIRECTIONS[dimension][bit])
+}
+
+/// Brent Burley's hash-based Owen scrambling, from
+/// "Practical Hash-based Owen Scrambling"
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+/// Owen scrambled Sobol points, in independently shuffled and
+/// scrambled sets of four dimensions (Burley's padding)
+pub struct SobolSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: usize
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler { seed, pixel: 0, index: 0, dimension: 0 }
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.pixel = pixel_hash(self.seed, x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let set_seed = mix64(self.pixel ^ (self.dimension / 4) as u64) as u32;
+        let shuffled = nested_uniform_scramble(self.index, set_seed);
+        let d = self.dimension % 4;
+        let value = nested_uniform_scramble(sobol(shuffled, d), mix64(set_seed as u64 ^ d as u64) as u32);
+        self.dimension += 1;
+        u32_to_unit_f32(value)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        // both within the same set of four
+        if self.dimension % 4 == 3 {
+            self.dimension += 1;
+        }
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sobol() {
+        let first: Vec<f32> = (0..8).map(|i| u32_to_unit_f32(sobol(i, 1))).collect();
+        assert_eq!(vec![0.0, 0.5, 0.75, 0.25, 0.625, 0.125, 0.375, 0.875], first);
+        let mut permutation: Vec<u32> = (0..7).map(|i| permute(i, 7, 12345)).collect();
+        permutation.sort();
+        assert_eq!((0..7).collect::<Vec<_>>(), permutation);
+    }
+
+    /// 16 samples of a pixel should get one in each cell of a 4x4 grid
+    #[test]
+    fn test_2d_stratification() {
+        for &kind in &[SamplerKind::Stratified, SamplerKind::Sobol] {
+            let mut sampler = kind.sampler(3, 16);
+            for dimension_pairs in 1..4 {
+                let mut cells = [false; 16];
+                for index in 0..16 {
+                    sampler.start_sample(5, 7, index);
+                    let mut point = (0.0, 0.0);
+                    for _ in 0..dimension_pairs {
+                        point = sampler.get_2d();
+                    }
+                    let cell = (point.0 * 4.0) as usize + 4 * (point.1 * 4.0) as usize;
+                    assert!(!cells[cell], "{:?} sample {} falls in a used cell", kind, index);
+                    cells[cell] = true;
+                }
+            }
+        }
+    }
+
+    /// later batches of a pixel should be spread as evenly as the first
+    #[test]
+    fn test_batches() {
+        for &(kind, columns, rows) in &[(SamplerKind::Sobol, 4, 4), (SamplerKind::Halton, 2, 3)] {
+            let batch = kind.batch_size().unwrap();
+            assert_eq!(columns * rows, batch);
+            let mut sampler = kind.sampler(3, 64);
+            for first in (batch..4*batch).step_by(batch as usize) {
+                let mut cells = vec![false; batch as usize];
+                for index in first..first + batch {
+                    sampler.start_sample(5, 7, index);
+                    let (x, y) = sampler.get_2d();
+                    let cell = (x * columns as f32) as u32 + columns * (y * rows as f32) as u32;
+                    assert!(!cells[cell as usize], "{:?} sample {} falls in a used cell", kind, index);
+                    cells[cell as usize] = true;
+                }
+            }
+        }
+        assert_eq!(None, SamplerKind::Stratified.batch_size());
+    }
+
+    /// estimates the area of a quarter disk, 16 samples at a time
+    #[test]
+    fn test_error_reduction() {
+        let error = |kind: SamplerKind| {
+            let mut sampler = kind.sampler(1, 16);
+            let mut squared_error = 0.0;
+            for pixel in 0..200 {
+                let mut inside = 0;
+                for index in 0..16 {
+                    sampler.start_sample(pixel, 0, index);
+                    let (x, y) = sampler.get_2d();
+                    if x*x + y*y < 1.0 {
+                        inside += 1;
+                    }
+                }
+                let estimate = inside as f32 / 16.0;
+                squared_error += (estimate - std::f32::consts::PI / 4.0).powi(2);
+            }
+            squared_error
+        };
+        let independent = error(SamplerKind::Independent);
+        for &kind in &[SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol] {
+            assert!(error(kind) < independent / 2.0, "{:?} {} {}", kind, error(kind), independent);
+        }
+    }
+}
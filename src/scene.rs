@@ -0,0 +1,998 @@
+// scenes: the shapes to render, the camera and the render settings.
+// They can be described in TOML files, see the scenes/ folder.
+
+use crate::{v3color::*, shapes::*, camera::*, material::*, texture::*, perlin::*, rng::*,
+            transform::*, medium::*, mesh::*, image::*, worley::*, obj, ply};
+
+use rand::Rng;
+use serde::Deserialize;
+use toml::Spanned;
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// what a ray sees when it doesn't hit anything
+pub enum Background {
+    Sky,
+    Solid(Color)
+}
+
+impl Background {
+    pub fn color(&self, ray: &Ray) -> V3 {
+        match self {
+            Background::Sky => {
+                let unit_direction = ray.direction.unit();
+                let t = 0.5 * (unit_direction.y + 1.0);
+                (1.0-t) * V3 { x: 1.0, y: 1.0, z: 1.0 }
+                    + t*V3 { x: 0.5, y: 0.7, z: 1.0 }
+            }
+            Background::Solid(color) => color.to_v3()
+        }
+    }
+}
+
+pub struct RenderSettings {
+    pub width: i32,
+    pub height: i32,
+    pub samples: i32,
+    pub max_depth: i32
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            width: 800,
+            height: 400,
+            samples: 100,
+            max_depth: 50
+        }
+    }
+}
+
+/// the owned counterpart of `CameraParams`
+pub struct CameraSettings {
+    pub look_from: V3,
+    pub look_at: V3,
+    pub vup: V3,
+    pub vert_fov_deg: f32,
+    pub aperture: f32,
+    pub focus_dist: f32,
+    /// defaults to the aspect of the rendered image
+    pub aspect: Option<f32>,
+    pub time1: f32,
+    pub time2: f32
+}
+
+impl CameraSettings {
+    pub fn to_camera(&self, settings: &RenderSettings) -> Camera {
+        Camera::new(CameraParams {
+            look_from: &self.look_from,
+            look_at: &self.look_at,
+            vup: &self.vup,
+            vert_fov_deg: self.vert_fov_deg,
+            aspect: self.aspect.unwrap_or(settings.width as f32 / settings.height as f32),
+            aperture: self.aperture,
+            focus_dist: self.focus_dist,
+            time1: self.time1,
+            time2: self.time2
+        })
+    }
+}
+
+pub struct Scene {
+    pub objects: Vec<Box<dyn Shape>>,
+    pub background: Background,
+    pub camera: CameraSettings,
+    pub settings: RenderSettings
+}
+
+#[derive(Debug)]
+pub struct SceneError {
+    pub line: Option<usize>,
+    pub message: String
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message)
+        }
+    }
+}
+
+/// `seed` drives the random parts of the scene, like noise textures
+pub fn load_scene(path: &Path, seed: u64) -> Result<Scene, SceneError> {
+    let text = fs::read_to_string(path).map_err(|e| SceneError {
+        line: None,
+        message: format!("can't read {}: {}", path.display(), e)
+    })?;
+    parse_scene(&text, path.parent().unwrap_or_else(|| Path::new(".")), seed)
+}
+
+// the descriptions, as read from the files. Colors and
+// vectors are written as arrays of three numbers.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    #[serde(default)]
+    render: RenderDesc,
+    camera: CameraDesc,
+    #[serde(default)]
+    textures: HashMap<String, Spanned<TextureDesc>>,
+    #[serde(default)]
+    materials: HashMap<String, Spanned<MaterialDesc>>,
+    shapes: Vec<Spanned<ShapeDesc>>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct RenderDesc {
+    width: i32,
+    height: i32,
+    samples: i32,
+    max_depth: i32,
+    background: BackgroundDesc
+}
+
+impl Default for RenderDesc {
+    fn default() -> RenderDesc {
+        let settings = RenderSettings::default();
+        RenderDesc {
+            width: settings.width,
+            height: settings.height,
+            samples: settings.samples,
+            max_depth: settings.max_depth,
+            background: BackgroundDesc::Sky
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum BackgroundDesc {
+    Sky,
+    Solid { color: [f32; 3] }
+}
+
+fn default_vup() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_time2() -> f32 {
+    1.0
+}
+
+fn default_checker_frequency() -> f32 {
+    10.0
+}
+
+fn default_uv_squares() -> [f32; 2] {
+    [8.0, 8.0]
+}
+
+fn default_noise_frequency() -> f32 {
+    4.0
+}
+
+fn default_octaves() -> u32 {
+    1
+}
+
+fn default_marble_frequency() -> f32 {
+    4.0
+}
+
+fn default_wood_frequency() -> f32 {
+    6.0
+}
+
+fn default_clouds_frequency() -> f32 {
+    1.0
+}
+
+fn default_pattern_octaves() -> u32 {
+    6
+}
+
+fn default_marble_turbulence() -> f32 {
+    5.0
+}
+
+fn default_wood_turbulence() -> f32 {
+    0.5
+}
+
+fn default_gain() -> f32 {
+    0.5
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    look_from: [f32; 3],
+    look_at: [f32; 3],
+    #[serde(default = "default_vup")]
+    vup: [f32; 3],
+    vert_fov_deg: f32,
+    #[serde(default)]
+    aperture: f32,
+    /// defaults to the distance between look_from and look_at
+    focus_dist: Option<f32>,
+    aspect: Option<f32>,
+    #[serde(default)]
+    time1: f32,
+    #[serde(default = "default_time2")]
+    time2: f32
+}
+
+/// references to other textures are by name
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDesc {
+    Constant { color: [f32; 3] },
+    /// the checks are π/frequency wide
+    Checker {
+        odd: String, even: String,
+        #[serde(default = "default_checker_frequency")]
+        frequency: f32
+    },
+    SphericalChecker {
+        odd: String, even: String,
+        #[serde(default = "default_checker_frequency")]
+        frequency: f32
+    },
+    /// `squares` across u and v, from 0 to 1
+    UvChecker {
+        odd: String, even: String,
+        #[serde(default = "default_uv_squares")]
+        squares: [f32; 2]
+    },
+    /// more than one octave adds finer detail
+    Noise {
+        #[serde(default = "default_noise_frequency")]
+        frequency: f32,
+        #[serde(default = "default_octaves")]
+        octaves: u32
+    },
+    /// the next three are gray, see ramp to color them
+    Marble {
+        #[serde(default = "default_marble_frequency")]
+        frequency: f32,
+        #[serde(default = "default_marble_turbulence")]
+        turbulence: f32,
+        #[serde(default = "default_pattern_octaves")]
+        octaves: u32
+    },
+    /// the frequency is in rings per unit, the turbulence in rings
+    Wood {
+        #[serde(default = "default_wood_frequency")]
+        frequency: f32,
+        #[serde(default = "default_wood_turbulence")]
+        turbulence: f32,
+        #[serde(default = "default_pattern_octaves")]
+        octaves: u32
+    },
+    Clouds {
+        #[serde(default = "default_clouds_frequency")]
+        frequency: f32,
+        #[serde(default = "default_pattern_octaves")]
+        octaves: u32,
+        #[serde(default = "default_gain")]
+        gain: f32
+    },
+    /// `feature` is "f1" (the default), "f2" or "f2-f1", `metric` is
+    /// "euclidean" (the default), "manhattan" or "chebyshev". The values
+    /// are the distances in cells over the farthest F2 can be, √3, 3 and 1.5
+    /// respectively. Without a seed, the points change with the scene's.
+    Worley {
+        #[serde(default = "default_noise_frequency")]
+        frequency: f32,
+        feature: Option<String>,
+        metric: Option<String>,
+        seed: Option<u64>
+    },
+    /// maps the luminance of `input` to colors
+    Ramp { input: String, stops: Vec<RampStopDesc> },
+    /// a PNG or PPM file, relative to the scene file.
+    /// `wrap` is "repeat" (the default) or "clamp".
+    Image { path: String, wrap: Option<String> },
+    /// `a` where `amount` is black, `b` where it's white
+    Mix { a: String, b: String, amount: String },
+    Multiply { a: String, b: String },
+    Add { a: String, b: String },
+    Invert { input: String },
+    /// moves, turns or stretches the pattern of a solid texture
+    Transformed { input: String, transform: Vec<TransformDesc> }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RampStopDesc {
+    position: f32,
+    color: [f32; 3]
+}
+
+/// references to textures are by name
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDesc {
+    Lambertian { albedo: String },
+    Metal { albedo: [f32; 3], fuzz: f32 },
+    Dielectric { ref_idx: f32 },
+    DiffuseLight { emit: String }
+}
+
+/// references to materials and textures are by name
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ShapeDesc {
+    /// a negative radius turns the sphere inside out, for the
+    /// inner surface of hollow glass
+    Sphere { center: [f32; 3], radius: f32, material: String },
+    MovingSphere {
+        center0: [f32; 3], center1: [f32; 3],
+        time0: f32, time1: f32,
+        radius: f32, material: String
+    },
+    XyRect { x0: f32, x1: f32, y0: f32, y1: f32, k: f32, material: String },
+    XzRect { x0: f32, x1: f32, z0: f32, z1: f32, k: f32, material: String },
+    YzRect { y0: f32, y1: f32, z0: f32, z1: f32, k: f32, material: String },
+    Box { pmin: [f32; 3], pmax: [f32; 3], material: String },
+    Plane { point: [f32; 3], normal: [f32; 3], material: String },
+    Triangle {
+        vertices: [[f32; 3]; 3],
+        normals: Option<[[f32; 3]; 3]>,
+        uvs: Option<[[f32; 2]; 3]>,
+        material: String
+    },
+    /// an OBJ or PLY file, relative to the scene file. Using the same
+    /// file several times shares the triangles. The material
+    /// is only supported for PLY files, OBJ files have their own.
+    Mesh { path: String, material: Option<String> },
+    ConstantMedium { boundary: Box<ShapeDesc>, density: f32, albedo: String },
+    Transformed { shape: Box<ShapeDesc>, transform: Vec<TransformDesc> },
+    FlipNormals { shape: Box<ShapeDesc> }
+}
+
+/// the steps of a transform are applied in order
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TransformDesc {
+    Translate { offset: [f32; 3] },
+    Rotate { axis: [f32; 3], degrees: f32 },
+    Scale { factors: [f32; 3] }
+}
+
+fn v3(a: &[f32; 3]) -> V3 {
+    V3 { x: a[0], y: a[1], z: a[2] }
+}
+
+fn color(a: &[f32; 3]) -> Color {
+    Color { r: a[0], g: a[1], b: a[2] }
+}
+
+fn transform_matrix(steps: &[TransformDesc]) -> M4 {
+    steps.iter().fold(M4::identity(), |m, step| match step {
+        TransformDesc::Translate { offset } => M4::translation(&v3(offset)) * m,
+        TransformDesc::Rotate { axis, degrees } => M4::rotation(&v3(axis), *degrees) * m,
+        TransformDesc::Scale { factors } => M4::scaling(&v3(factors)) * m
+    })
+}
+
+/// textures may reference each other, cycles are caught
+/// separately but this bounds the recursion anyway
+static MAX_TEXTURE_NESTING: usize = 32;
+
+/// turns the descriptions into actual objects
+struct SceneBuilder<'a> {
+    text: &'a str,
+    base_dir: &'a Path,
+    desc: &'a SceneDesc,
+    meshes: RefCell<HashMap<PathBuf, Arc<Mesh>>>,
+    images: RefCell<HashMap<PathBuf, Arc<Image>>>,
+    /// each name is built once, so that everything referring to
+    /// a random texture sees the same pattern
+    textures: RefCell<HashMap<String, Arc<dyn Texture>>>,
+    materials: RefCell<HashMap<String, Arc<dyn Material>>>,
+    rng: RefCell<Pcg32>
+}
+
+impl<'a> SceneBuilder<'a> {
+    fn line(&self, offset: usize) -> usize {
+        self.text[..offset].matches('\n').count() + 1
+    }
+
+    fn error<T>(&self, span: &std::ops::Range<usize>, message: String) -> Result<T, SceneError> {
+        Err(SceneError { line: Some(self.line(span.start)), message })
+    }
+
+    /// `path` holds the textures being built that lead to this one
+    fn texture<'n>(&self, name: &'n str, span: &std::ops::Range<usize>, path: &[&'n str]) -> Result<Box<dyn Texture>, SceneError> {
+        if let Some(texture) = self.textures.borrow().get(name) {
+            return Ok(Box::new(texture.clone()));
+        }
+        let texture: Arc<dyn Texture> = Arc::from(self.build_texture(name, span, path)?);
+        self.textures.borrow_mut().insert(name.to_string(), texture.clone());
+        Ok(Box::new(texture))
+    }
+
+    fn build_texture<'n>(&self, name: &'n str, span: &std::ops::Range<usize>, path: &[&'n str]) -> Result<Box<dyn Texture>, SceneError> {
+        if path.contains(&name) {
+            return self.error(span, format!("texture {} references itself", name));
+        }
+        if path.len() >= MAX_TEXTURE_NESTING {
+            return self.error(span, format!("texture {} is nested more than {} deep", name, MAX_TEXTURE_NESTING));
+        }
+        let desc = match self.desc.textures.get(name) {
+            Some(desc) => desc,
+            None => return self.error(span, format!("unknown texture: {}", name))
+        };
+        let span = desc.span();
+        let path = [path, &[name]].concat();
+        Ok(match desc.get_ref() {
+            TextureDesc::Constant { color: c } => Box::new(ConstantTexture { color: color(c) }),
+            TextureDesc::Checker { odd, even, frequency } => Box::new(CheckerTexture {
+                odd: self.texture(odd, &span, &path)?,
+                even: self.texture(even, &span, &path)?,
+                frequency: *frequency
+            }),
+            TextureDesc::SphericalChecker { odd, even, frequency } => Box::new(SphericalCheckerTexture {
+                odd: self.texture(odd, &span, &path)?,
+                even: self.texture(even, &span, &path)?,
+                frequency: *frequency
+            }),
+            TextureDesc::UvChecker { odd, even, squares } => Box::new(UvCheckerTexture {
+                odd: self.texture(odd, &span, &path)?,
+                even: self.texture(even, &span, &path)?,
+                squares_u: squares[0],
+                squares_v: squares[1]
+            }),
+            TextureDesc::Noise { octaves: 0, .. } | TextureDesc::Marble { octaves: 0, .. }
+            | TextureDesc::Wood { octaves: 0, .. } | TextureDesc::Clouds { octaves: 0, .. } =>
+                return self.error(&span, format!("texture {} needs at least one octave", name)),
+            TextureDesc::Noise { frequency, octaves } => Box::new(NoiseTexture {
+                perlin: Perlin::new(&mut self.rng.borrow_mut()),
+                frequency: *frequency,
+                octaves: *octaves
+            }),
+            TextureDesc::Marble { frequency, turbulence, octaves } => Box::new(MarbleTexture {
+                perlin: Perlin::new(&mut self.rng.borrow_mut()),
+                frequency: *frequency,
+                turbulence: *turbulence,
+                octaves: *octaves
+            }),
+            TextureDesc::Wood { frequency, turbulence, octaves } => Box::new(WoodTexture {
+                perlin: Perlin::new(&mut self.rng.borrow_mut()),
+                frequency: *frequency,
+                turbulence: *turbulence,
+                octaves: *octaves
+            }),
+            TextureDesc::Clouds { frequency, octaves, gain } => Box::new(CloudsTexture {
+                perlin: Perlin::new(&mut self.rng.borrow_mut()),
+                frequency: *frequency,
+                octaves: *octaves,
+                gain: *gain
+            }),
+            TextureDesc::Worley { frequency, feature, metric, seed } => {
+                let feature = match feature {
+                    Some(name) => match WorleyFeature::from_name(name) {
+                        Some(feature) => feature,
+                        None => return self.error(&span, format!("unknown Worley feature: {}", name))
+                    },
+                    None => WorleyFeature::F1
+                };
+                let metric = match metric {
+                    Some(name) => match DistanceMetric::from_name(name) {
+                        Some(metric) => metric,
+                        None => return self.error(&span, format!("unknown distance metric: {}", name))
+                    },
+                    None => DistanceMetric::Euclidean
+                };
+                let seed = seed.unwrap_or_else(|| self.rng.borrow_mut().gen());
+                Box::new(WorleyTexture { worley: Worley { seed }, frequency: *frequency, feature, metric })
+            },
+            TextureDesc::Ramp { input, stops } => {
+                if stops.is_empty() {
+                    return self.error(&span, format!("ramp {} has no stops", name));
+                }
+                Box::new(RampTexture {
+                    input: self.texture(input, &span, &path)?,
+                    ramp: ColorRamp::new(stops.iter().map(|s| (s.position, color(&s.color))).collect())
+                })
+            },
+            TextureDesc::Image { path, wrap } => {
+                let wrap = match wrap {
+                    Some(name) => match WrapMode::from_name(name) {
+                        Some(wrap) => wrap,
+                        None => return self.error(&span, format!("unknown wrap mode: {}", name))
+                    },
+                    None => WrapMode::Repeat
+                };
+                Box::new(ImageTexture { image: self.image(path, &span)?, wrap })
+            },
+            TextureDesc::Mix { a, b, amount } => Box::new(MixTexture {
+                a: self.texture(a, &span, &path)?,
+                b: self.texture(b, &span, &path)?,
+                amount: self.texture(amount, &span, &path)?
+            }),
+            TextureDesc::Multiply { a, b } => Box::new(MultiplyTexture {
+                a: self.texture(a, &span, &path)?,
+                b: self.texture(b, &span, &path)?
+            }),
+            TextureDesc::Add { a, b } => Box::new(AddTexture {
+                a: self.texture(a, &span, &path)?,
+                b: self.texture(b, &span, &path)?
+            }),
+            TextureDesc::Invert { input } => Box::new(InvertTexture {
+                input: self.texture(input, &span, &path)?
+            }),
+            TextureDesc::Transformed { input, transform } => {
+                let matrix = transform_matrix(transform);
+                if matrix.inverse().is_none() {
+                    return self.error(&span, format!("the transform of texture {} can't be inverted", name));
+                }
+                Box::new(TransformedTexture::new(self.texture(input, &span, &path)?, matrix))
+            }
+        })
+    }
+
+    /// several textures can use the same file
+    fn image(&self, path: &str, span: &std::ops::Range<usize>) -> Result<Arc<Image>, SceneError> {
+        let full_path = self.base_dir.join(path);
+        if let Some(image) = self.images.borrow().get(&full_path) {
+            return Ok(image.clone());
+        }
+        let image = Arc::new(load_image(&full_path)
+            .or_else(|e| self.error(span, format!("loading {}: {}", path, e)))?);
+        self.images.borrow_mut().insert(full_path, image.clone());
+        Ok(image)
+    }
+
+    fn material(&self, name: &str, span: &std::ops::Range<usize>) -> Result<Box<dyn Material>, SceneError> {
+        Ok(Box::new(self.shared_material(name, span)?))
+    }
+
+    fn shared_material(&self, name: &str, span: &std::ops::Range<usize>) -> Result<Arc<dyn Material>, SceneError> {
+        if let Some(material) = self.materials.borrow().get(name) {
+            return Ok(material.clone());
+        }
+        let material: Arc<dyn Material> = Arc::from(self.build_material(name, span)?);
+        self.materials.borrow_mut().insert(name.to_string(), material.clone());
+        Ok(material)
+    }
+
+    fn build_material(&self, name: &str, span: &std::ops::Range<usize>) -> Result<Box<dyn Material>, SceneError> {
+        let desc = match self.desc.materials.get(name) {
+            Some(desc) => desc,
+            None => return self.error(span, format!("unknown material: {}", name))
+        };
+        let span = desc.span();
+        Ok(match desc.get_ref() {
+            MaterialDesc::Lambertian { albedo } => Box::new(Lambertian {
+                albedo: self.texture(albedo, &span, &[])?
+            }),
+            MaterialDesc::Metal { albedo, fuzz } => Box::new(Metal {
+                albedo: color(albedo),
+                fuzz: *fuzz
+            }),
+            MaterialDesc::Dielectric { ref_idx } => Box::new(Dielectric { ref_idx: *ref_idx }),
+            MaterialDesc::DiffuseLight { emit } => Box::new(DiffuseLight {
+                emit: self.texture(emit, &span, &[])?
+            })
+        })
+    }
+
+    fn mesh(&self, path: &str, material: &Option<String>, span: &std::ops::Range<usize>) -> Result<Arc<Mesh>, SceneError> {
+        let full_path = self.base_dir.join(path);
+        if let Some(mesh) = self.meshes.borrow().get(&full_path) {
+            return Ok(mesh.clone());
+        }
+        let mesh = match (path.ends_with(".ply"), material) {
+            (true, material) => ply::load_ply(&full_path, match material {
+                Some(m) => Some(self.shared_material(m, span)?),
+                None => None
+            }),
+            (false, None) => obj::load_obj(&full_path),
+            (false, Some(_)) => return self.error(span, "only PLY meshes can have a material".to_string())
+        };
+        let mesh = Arc::new(mesh.or_else(|e| self.error(span, format!("loading {}: {}", path, e)))?);
+        self.meshes.borrow_mut().insert(full_path, mesh.clone());
+        Ok(mesh)
+    }
+
+    fn shape(&self, desc: &ShapeDesc, span: &std::ops::Range<usize>) -> Result<Box<dyn Shape>, SceneError> {
+        let material = |name: &str| self.material(name, span);
+        Ok(match desc {
+            ShapeDesc::Sphere { center, radius, material: m } => Box::new(Sphere {
+                center: v3(center),
+                radius: *radius,
+                material: material(m)?
+            }),
+            ShapeDesc::MovingSphere { center0, center1, time0, time1, radius, material: m } => Box::new(MovingSphere {
+                center0: v3(center0),
+                center1: v3(center1),
+                time0: *time0,
+                time1: *time1,
+                radius: *radius,
+                material: material(m)?
+            }),
+            ShapeDesc::XyRect { x0, x1, y0, y1, k, material: m } => Box::new(XyRect {
+                x0: *x0, x1: *x1, y0: *y0, y1: *y1, k: *k,
+                material: material(m)?
+            }),
+            ShapeDesc::XzRect { x0, x1, z0, z1, k, material: m } => Box::new(XzRect {
+                x0: *x0, x1: *x1, z0: *z0, z1: *z1, k: *k,
+                material: material(m)?
+            }),
+            ShapeDesc::YzRect { y0, y1, z0, z1, k, material: m } => Box::new(YzRect {
+                y0: *y0, y1: *y1, z0: *z0, z1: *z1, k: *k,
+                material: material(m)?
+            }),
+            ShapeDesc::Box { pmin, pmax, material: m } => Box::new(BoxShape {
+                pmin: v3(pmin),
+                pmax: v3(pmax),
+                material: material(m)?
+            }),
+            ShapeDesc::Plane { point, normal, material: m } => {
+                if v3(normal).length() == 0.0 {
+                    return self.error(span, "the normal of a plane can't be zero".to_string());
+                }
+                Box::new(InfinitePlane {
+                    point: v3(point),
+                    normal: v3(normal),
+                    material: material(m)?
+                })
+            },
+            ShapeDesc::Triangle { vertices, normals, uvs, material: m } => Box::new(Triangle {
+                vertices: [v3(&vertices[0]), v3(&vertices[1]), v3(&vertices[2])],
+                normals: normals.map(|n| [v3(&n[0]), v3(&n[1]), v3(&n[2])]),
+                uvs: uvs.map(|t| [(t[0][0], t[0][1]), (t[1][0], t[1][1]), (t[2][0], t[2][1])]),
+                material: self.shared_material(m, span)?
+            }),
+            ShapeDesc::Mesh { path, material: m } => Box::new(self.mesh(path, m, span)?),
+            ShapeDesc::ConstantMedium { boundary, density, albedo } => {
+                if *density <= 0.0 {
+                    return self.error(span, format!("the density must be positive, got {}", density));
+                }
+                Box::new(ConstantMedium::new(
+                    self.shape(boundary, span)?, *density, self.texture(albedo, span, &[])?))
+            },
+            ShapeDesc::Transformed { shape, transform } => {
+                let matrix = transform_matrix(transform);
+                if matrix.inverse().is_none() {
+                    return self.error(span, "the transform can't be inverted".to_string());
+                }
+                let shape: Arc<dyn Shape> = match shape.as_ref() {
+                    // share the triangles with the other instances
+                    ShapeDesc::Mesh { path, material: m } => self.mesh(path, m, span)?,
+                    other => Arc::from(self.shape(other, span)?)
+                };
+                Box::new(Transformed::new(shape, matrix))
+            },
+            ShapeDesc::FlipNormals { shape } => Box::new(FlipNormals {
+                shape: self.shape(shape, span)?
+            })
+        })
+    }
+}
+
+fn parse_scene(text: &str, base_dir: &Path, seed: u64) -> Result<Scene, SceneError> {
+    let desc: SceneDesc = toml::from_str(text).map_err(|e| SceneError {
+        line: e.span().map(|s| text[..s.start].matches('\n').count() + 1),
+        message: e.message().to_string()
+    })?;
+    let builder = SceneBuilder {
+        text, base_dir,
+        desc: &desc,
+        meshes: RefCell::new(HashMap::new()),
+        images: RefCell::new(HashMap::new()),
+        textures: RefCell::new(HashMap::new()),
+        materials: RefCell::new(HashMap::new()),
+        rng: RefCell::new(Pcg32::new(seed, SCENE_STREAM))
+    };
+    let objects = desc.shapes.iter()
+        .map(|s| builder.shape(s.get_ref(), &s.span()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if objects.is_empty() {
+        return Err(SceneError { line: None, message: "the scene has no shapes".to_string() });
+    }
+    let render = &desc.render;
+    if render.width <= 0 || render.height <= 0 || render.samples <= 0 || render.max_depth <= 0 {
+        return Err(SceneError {
+            line: None,
+            message: "the width, height, samples and max_depth must be positive".to_string()
+        });
+    }
+    let camera = &desc.camera;
+    Ok(Scene {
+        objects,
+        background: match &render.background {
+            BackgroundDesc::Sky => Background::Sky,
+            BackgroundDesc::Solid { color: c } => Background::Solid(color(c))
+        },
+        camera: CameraSettings {
+            look_from: v3(&camera.look_from),
+            look_at: v3(&camera.look_at),
+            vup: v3(&camera.vup),
+            vert_fov_deg: camera.vert_fov_deg,
+            aperture: camera.aperture,
+            focus_dist: camera.focus_dist.unwrap_or_else(
+                || (v3(&camera.look_from) - v3(&camera.look_at)).length()),
+            aspect: camera.aspect,
+            time1: camera.time1,
+            time2: camera.time2
+        },
+        settings: RenderSettings {
+            width: render.width,
+            height: render.height,
+            samples: render.samples,
+            max_depth: render.max_depth
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Scene, SceneError> {
+        parse_scene(text, Path::new("."), 0)
+    }
+
+    fn error_line(text: &str) -> Option<usize> {
+        match parse(text) {
+            Ok(_) => panic!("the scene should not parse"),
+            Err(e) => e.line
+        }
+    }
+
+    fn error_message(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("the scene should not parse"),
+            Err(e) => e.message
+        }
+    }
+
+    static CAMERA: &str = "
+[camera]
+look_from = [0, 0, 5]
+look_at = [0, 0, 0]
+vert_fov_deg = 40
+";
+
+    #[test]
+    fn test_parse_scene() {
+        let scene = parse(&format!("{}
+[render]
+width = 20
+height = 10
+background = {{ type = \"solid\", color = [0, 0, 0] }}
+
+[textures.white]
+type = \"constant\"
+color = [1, 1, 1]
+
+[materials.light]
+type = \"diffuse_light\"
+emit = \"white\"
+
+[[shapes]]
+type = \"transformed\"
+transform = [
+    {{ type = \"scale\", factors = [2, 2, 2] }},
+    {{ type = \"translate\", offset = [0, 1, 0] }}
+]
+[shapes.shape]
+type = \"sphere\"
+center = [0, 0, 0]
+radius = 1
+material = \"light\"
+", CAMERA)).unwrap();
+        assert_eq!(20, scene.settings.width);
+        assert_eq!(50, scene.settings.max_depth);
+        assert_eq!(5.0, scene.camera.focus_dist);
+        let hit = scene.objects[0].hit(&Ray {
+            origin: V3 {x: 0.0, y: 1.0, z: 5.0},
+            direction: V3 {x: 0.0, y: 0.0, z: -1.0},
+            time: 0.0
+        }, &(0.001..f32::MAX)).expect("should hit the sphere");
+        assert!((hit.t - 3.0).abs() < 1e-5);
+        assert_eq!(Color { r: 1.0, g: 1.0, b: 1.0 }, hit.material.emitted(hit.u, hit.v, &hit.p));
+    }
+
+    #[test]
+    fn test_shared_textures() {
+        // two lights emitting the same random textures, on the same sphere
+        let mut text = format!("{}
+[textures.noise]
+type = \"noise\"
+
+[textures.cells]
+type = \"worley\"
+", CAMERA);
+        for (material, texture) in &[("noise_a", "noise"), ("noise_b", "noise"), ("cells_a", "cells"), ("cells_b", "cells")] {
+            text += &format!("
+[materials.{}]
+type = \"diffuse_light\"
+emit = \"{}\"
+
+[[shapes]]
+type = \"sphere\"
+center = [0, 0, 0]
+radius = 1
+material = \"{}\"
+", material, texture, material);
+        }
+        let scene = parse(&text).unwrap();
+        for i in 0..20 {
+            let ray = Ray {
+                origin: V3 {x: i as f32 / 20.0 - 0.5, y: 0.3, z: 5.0},
+                direction: V3 {x: 0.0, y: 0.0, z: -1.0},
+                time: 0.0
+            };
+            let emitted: Vec<Color> = scene.objects.iter().map(|o| {
+                let hit = o.hit(&ray, &(0.001..f32::MAX)).unwrap();
+                hit.material.emitted(hit.u, hit.v, &hit.p)
+            }).collect();
+            assert_eq!(emitted[0], emitted[1]);
+            assert_eq!(emitted[2], emitted[3]);
+        }
+
+        // each texture is built once, not once per reference
+        let mut chain = format!("{}
+[textures.t30]
+type = \"constant\"
+color = [1, 1, 1]
+", CAMERA);
+        for i in 0..30 {
+            chain += &format!("
+[textures.t{}]
+type = \"checker\"
+odd = \"t{}\"
+even = \"t{}\"
+", i, i + 1, i + 1);
+        }
+        chain += "
+[materials.checkers]
+type = \"lambertian\"
+albedo = \"t0\"
+
+[[shapes]]
+type = \"sphere\"
+center = [0, 0, 0]
+radius = 1
+material = \"checkers\"
+";
+        assert!(parse(&chain).is_ok());
+    }
+
+    #[test]
+    fn test_example_scenes() {
+        // they must load as they are, with the files they reference
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "toml") {
+                if let Err(e) = load_scene(&path, 0) {
+                    panic!("{}: {}", path.display(), e.message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_scene_errors() {
+        // unknown shape type, reported on the type itself
+        assert_eq!(Some(8), error_line(&format!("{}
+[[shapes]]
+type = \"spher\"
+center = [0, 0, 0]
+", CAMERA)));
+        // missing field
+        assert_eq!(Some(7), error_line(&format!("{}
+[[shapes]]
+type = \"sphere\"
+center = [0, 0, 0]
+material = \"glass\"
+", CAMERA)));
+        // unknown material
+        assert_eq!(Some(7), error_line(&format!("{}
+[[shapes]]
+type = \"sphere\"
+center = [0, 0, 0]
+radius = 1
+material = \"glass\"
+", CAMERA)));
+        // a texture referencing itself
+        assert_eq!(Some(7), error_line(&format!("{}
+[textures.loop]
+type = \"checker\"
+odd = \"loop\"
+even = \"loop\"
+
+[materials.looping]
+type = \"lambertian\"
+albedo = \"loop\"
+
+[[shapes]]
+type = \"sphere\"
+center = [0, 0, 0]
+radius = 1
+material = \"looping\"
+", CAMERA)));
+        // an indirect cycle
+        assert!(error_message(&format!("{}
+[textures.a]
+type = \"checker\"
+odd = \"b\"
+even = \"b\"
+
+[textures.b]
+type = \"checker\"
+odd = \"a\"
+even = \"a\"
+
+[materials.looping]
+type = \"lambertian\"
+albedo = \"a\"
+
+[[shapes]]
+type = \"sphere\"
+center = [0, 0, 0]
+radius = 1
+material = \"looping\"
+", CAMERA)).contains("references itself"));
+        // no cycle, just a very long chain of textures
+        let mut chain = format!("{}
+[textures.t40]
+type = \"constant\"
+color = [1, 1, 1]
+", CAMERA);
+        for i in 0..40 {
+            chain += &format!("
+[textures.t{}]
+type = \"checker\"
+odd = \"t{}\"
+even = \"t40\"
+", i, i + 1);
+        }
+        chain += "
+[materials.deep]
+type = \"lambertian\"
+albedo = \"t0\"
+
+[[shapes]]
+type = \"sphere\"
+center = [0, 0, 0]
+radius = 1
+material = \"deep\"
+";
+        assert!(error_message(&chain).contains("nested more than 32 deep"));
+        // a texture squashed flat
+        assert_eq!(Some(11), error_line(&format!("{}
+[textures.white]
+type = \"constant\"
+color = [1, 1, 1]
+
+[textures.flat]
+type = \"transformed\"
+input = \"white\"
+transform = [{{ type = \"scale\", factors = [1, 0, 1] }}]
+
+[materials.flat]
+type = \"lambertian\"
+albedo = \"flat\"
+
+[[shapes]]
+type = \"sphere\"
+center = [0, 0, 0]
+radius = 1
+material = \"flat\"
+", CAMERA)));
+    }
+}
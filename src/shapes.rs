@@ -1,5 +1,8 @@
 use crate::{v3color::*, material::*, bvh::*};
 
+use std::f32::consts::PI;
+use std::sync::Arc;
+
 pub struct Ray {
     pub origin: V3,
     pub direction: V3,
@@ -10,23 +13,50 @@ impl Ray {
     pub fn point_at_parameter(&self, t: f32) -> V3 {
         self.origin + self.direction*t
     }
+
+    /// for the bounding box tests, which multiply instead of dividing
+    pub fn inverse_direction(&self) -> V3 {
+        V3 { x: 1.0 / self.direction.x, y: 1.0 / self.direction.y, z: 1.0 / self.direction.z }
+    }
 }
 
 #[derive(Copy, Clone)]
 pub struct HitRecord<'a> {
     pub t: f32,
     pub p: V3,
+    /// unit length, always on the side the ray came from
     pub normal: V3,
-    pub material: &'a Material
+    /// whether the ray hit the outside of the surface, where
+    /// the shape's own normal points
+    pub front_face: bool,
+    /// surface coordinates, for textures
+    pub u: f32,
+    pub v: f32,
+    pub material: &'a dyn Material
 }
 
-pub trait Shape: Sync {
+/// `front_face` and the normal facing the ray, from the outward one
+pub fn face_normal(ray: &Ray, outward_normal: &V3) -> (bool, V3) {
+    if V3::dot(&ray.direction, outward_normal) < 0.0 {
+        (true, *outward_normal)
+    } else {
+        (false, -outward_normal)
+    }
+}
+
+pub trait Shape: Send + Sync {
     fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>>;
 
-    /// at some point we should return an option because not
-    /// all primitives have bounding boxes (eg infinite planes)
-    /// but for now we don't implement or handle these so...
-    fn bounding_box(&self, t_range: &std::ops::Range<f32>) -> Aabb;
+    /// None for shapes that go on forever, like infinite planes
+    fn bounding_box(&self, t_range: &std::ops::Range<f32>) -> Option<Aabb>;
+}
+
+/// longitude and latitude of a point on the unit sphere, both in 0..1:
+/// u goes around the y axis starting from -x, v goes from the bottom up
+fn sphere_uv(p: &V3) -> (f32, f32) {
+    let theta = f32::acos((-p.y).clamp(-1.0, 1.0));
+    let phi = f32::atan2(-p.z, p.x) + PI;
+    (phi / (2.0*PI), theta / PI)
 }
 
 fn sphere_hit<'a>(ray: &Ray, sphere_center: &V3, sphere_radius: f32,
@@ -39,10 +69,14 @@ fn sphere_hit<'a>(ray: &Ray, sphere_center: &V3, sphere_radius: f32,
 
     let get_hit_record = |solution| {
         let point = ray.point_at_parameter(solution);
+        // a negative radius turns the sphere inside out
+        let (front_face, normal) = face_normal(ray, &((point - sphere_center) / sphere_radius));
+        let (u, v) = sphere_uv(&((point - sphere_center) / sphere_radius.abs()));
         Some(HitRecord {
             t: solution,
             p: point,
-            normal: (point - sphere_center) / sphere_radius,
+            normal, front_face,
+            u, v,
             material: &*sphere_material
         })
     };
@@ -62,6 +96,7 @@ fn sphere_hit<'a>(ray: &Ray, sphere_center: &V3, sphere_radius: f32,
 }
 
 fn sphere_bounding_box(center: &V3, radius: f32) -> Aabb {
+    let radius = radius.abs();
     Aabb {
         min: center - V3 { x: radius, y: radius, z: radius },
         max: center + V3 { x: radius, y: radius, z: radius }
@@ -70,6 +105,8 @@ fn sphere_bounding_box(center: &V3, radius: f32) -> Aabb {
 
 pub struct Sphere {
     pub center: V3,
+    /// negative for a sphere facing inwards, for instance
+    /// the inner surface of hollow glass
     pub radius: f32,
     pub material: Box<Material>
 }
@@ -79,8 +116,8 @@ impl Shape for Sphere {
         sphere_hit(ray, &self.center, self.radius, &*self.material, t_range)
     }
 
-    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Aabb {
-        sphere_bounding_box(&self.center, self.radius)
+    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        Some(sphere_bounding_box(&self.center, self.radius))
     }
 }
 
@@ -103,8 +140,613 @@ impl Shape for MovingSphere {
         sphere_hit(ray, &center, self.radius, &*self.material, t_range)
     }
 
-    fn bounding_box(&self, t_range: &std::ops::Range<f32>) -> Aabb {
-        sphere_bounding_box(&moving_sphere_center_at_time(&self, t_range.start), self.radius)
-            .union(&sphere_bounding_box(&moving_sphere_center_at_time(&self, t_range.end), self.radius))
+    fn bounding_box(&self, t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        Some(sphere_bounding_box(&moving_sphere_center_at_time(self, t_range.start), self.radius)
+            .union(&sphere_bounding_box(&moving_sphere_center_at_time(self, t_range.end), self.radius)))
+    }
+}
+
+/// shared shapes, for instance a mesh placed several times
+impl<S: Shape + ?Sized> Shape for Arc<S> {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        (**self).hit(ray, t_range)
+    }
+
+    fn bounding_box(&self, t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        (**self).bounding_box(t_range)
+    }
+}
+
+/// the rectangles are infinitely thin, but the bounding
+/// boxes must have some thickness for the BVH to work.
+static RECT_THICKNESS: f32 = 0.0001;
+
+/// describes how an axis-aligned rectangle is laid out:
+/// it spans the `a` and `b` axes and sits at a constant
+/// coordinate on the `k` axis.
+struct RectAxes {
+    a: fn(&V3) -> f32,
+    b: fn(&V3) -> f32,
+    k: fn(&V3) -> f32,
+    normal: V3
+}
+
+static XY_AXES: RectAxes = RectAxes {
+    a: V3::get_x, b: V3::get_y, k: V3::get_z,
+    normal: V3 { x: 0.0, y: 0.0, z: 1.0 }
+};
+
+static XZ_AXES: RectAxes = RectAxes {
+    a: V3::get_x, b: V3::get_z, k: V3::get_y,
+    normal: V3 { x: 0.0, y: 1.0, z: 0.0 }
+};
+
+static YZ_AXES: RectAxes = RectAxes {
+    a: V3::get_y, b: V3::get_z, k: V3::get_x,
+    normal: V3 { x: 1.0, y: 0.0, z: 0.0 }
+};
+
+#[allow(clippy::too_many_arguments)]
+fn aa_rect_hit<'a>(ray: &Ray, axes: &RectAxes, a_range: (f32, f32), b_range: (f32, f32), k: f32,
+        material: &'a dyn Material, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+    // a ray parallel to the rectangle gives an infinite or NaN t,
+    // which the range check rejects.
+    let t = (k - (axes.k)(&ray.origin)) / (axes.k)(&ray.direction);
+    if !t_range.contains(&t) {
+        return None;
+    }
+    let p = ray.point_at_parameter(t);
+    let (a, b) = ((axes.a)(&p), (axes.b)(&p));
+    if a < a_range.0 || a > a_range.1 || b < b_range.0 || b > b_range.1 {
+        return None;
+    }
+    let (front_face, normal) = face_normal(ray, &axes.normal);
+    Some(HitRecord {
+        t, p, normal, front_face,
+        u: (a - a_range.0) / (a_range.1 - a_range.0),
+        v: (b - b_range.0) / (b_range.1 - b_range.0),
+        material
+    })
+}
+
+/// rectangle in the XY plane, normal pointing towards +z
+pub struct XyRect {
+    pub x0: f32,
+    pub x1: f32,
+    pub y0: f32,
+    pub y1: f32,
+    pub k: f32,
+    pub material: Box<dyn Material>
+}
+
+impl Shape for XyRect {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        aa_rect_hit(ray, &XY_AXES, (self.x0, self.x1), (self.y0, self.y1), self.k, &*self.material, t_range)
+    }
+
+    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        Some(Aabb {
+            min: V3 { x: self.x0, y: self.y0, z: self.k - RECT_THICKNESS },
+            max: V3 { x: self.x1, y: self.y1, z: self.k + RECT_THICKNESS }
+        })
+    }
+}
+
+/// rectangle in the XZ plane, normal pointing towards +y
+pub struct XzRect {
+    pub x0: f32,
+    pub x1: f32,
+    pub z0: f32,
+    pub z1: f32,
+    pub k: f32,
+    pub material: Box<dyn Material>
+}
+
+impl Shape for XzRect {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        aa_rect_hit(ray, &XZ_AXES, (self.x0, self.x1), (self.z0, self.z1), self.k, &*self.material, t_range)
+    }
+
+    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        Some(Aabb {
+            min: V3 { x: self.x0, y: self.k - RECT_THICKNESS, z: self.z0 },
+            max: V3 { x: self.x1, y: self.k + RECT_THICKNESS, z: self.z1 }
+        })
+    }
+}
+
+/// rectangle in the YZ plane, normal pointing towards +x
+pub struct YzRect {
+    pub y0: f32,
+    pub y1: f32,
+    pub z0: f32,
+    pub z1: f32,
+    pub k: f32,
+    pub material: Box<dyn Material>
+}
+
+impl Shape for YzRect {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        aa_rect_hit(ray, &YZ_AXES, (self.y0, self.y1), (self.z0, self.z1), self.k, &*self.material, t_range)
+    }
+
+    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        Some(Aabb {
+            min: V3 { x: self.k - RECT_THICKNESS, y: self.y0, z: self.z0 },
+            max: V3 { x: self.k + RECT_THICKNESS, y: self.y1, z: self.z1 }
+        })
+    }
+}
+
+/// turns a shape inside out, for instance so that the
+/// walls of a room face inwards.
+pub struct FlipNormals {
+    pub shape: Box<dyn Shape>
+}
+
+impl Shape for FlipNormals {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        self.shape.hit(ray, t_range)
+            .map(|h| HitRecord { front_face: !h.front_face, ..h })
+    }
+
+    fn bounding_box(&self, t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        self.shape.bounding_box(t_range)
+    }
+}
+
+/// axis-aligned cuboid between two corners, made of six
+/// rectangles with normals pointing outwards.
+pub struct BoxShape {
+    pub pmin: V3,
+    pub pmax: V3,
+    pub material: Box<dyn Material>
+}
+
+impl Shape for BoxShape {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        let mut closest: Option<HitRecord<'a>> = None;
+        for axes in &[&XY_AXES, &XZ_AXES, &YZ_AXES] {
+            let a_range = ((axes.a)(&self.pmin), (axes.a)(&self.pmax));
+            let b_range = ((axes.b)(&self.pmin), (axes.b)(&self.pmax));
+            // the face at the min corner faces the other way
+            for &(k, outwards) in &[((axes.k)(&self.pmin), false), ((axes.k)(&self.pmax), true)] {
+                let t_end = closest.map_or(t_range.end, |h| h.t);
+                if let Some(h) = aa_rect_hit(ray, axes, a_range, b_range, k,
+                                             &*self.material, &(t_range.start..t_end)) {
+                    closest = Some(HitRecord { front_face: h.front_face == outwards, ..h });
+                }
+            }
+        }
+        closest
     }
-}
\ No newline at end of file
+
+    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        Some(Aabb { min: self.pmin, max: self.pmax })
+    }
+}
+
+/// goes on forever, so the BVH tests it against every ray
+pub struct InfinitePlane {
+    pub point: V3,
+    /// doesn't have to be of unit length
+    pub normal: V3,
+    pub material: Box<dyn Material>
+}
+
+impl Shape for InfinitePlane {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        let normal = self.normal.unit();
+        // a ray parallel to the plane gives an infinite or NaN t,
+        // which the range check rejects.
+        let t = V3::dot(&(self.point - ray.origin), &normal) / V3::dot(&ray.direction, &normal);
+        if !t_range.contains(&t) {
+            return None;
+        }
+        let p = ray.point_at_parameter(t);
+        // the coordinates along two directions in the plane, in world units
+        let helper = if normal.x.abs() < 0.9 {
+            V3 { x: 1.0, y: 0.0, z: 0.0 }
+        } else {
+            V3 { x: 0.0, y: 1.0, z: 0.0 }
+        };
+        let tangent = V3::cross(&helper, &normal).unit();
+        let bitangent = V3::cross(&normal, &tangent);
+        let offset = p - self.point;
+        let (front_face, shading_normal) = face_normal(ray, &normal);
+        Some(HitRecord {
+            t, p, front_face,
+            normal: shading_normal,
+            u: V3::dot(&offset, &tangent),
+            v: V3::dot(&offset, &bitangent),
+            material: &*self.material
+        })
+    }
+
+    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        None
+    }
+}
+
+/// Möller-Trumbore rejects rays whose determinant is smaller than
+/// this, they're parallel to the triangle (or the triangle is degenerate)
+static TRIANGLE_EPSILON: f32 = 1e-12;
+
+pub struct Triangle {
+    pub vertices: [V3; 3],
+    /// per-vertex normals, for smooth shading. When missing
+    /// the geometric normal is used, following the winding order.
+    pub normals: Option<[V3; 3]>,
+    /// per-vertex texture coordinates. When missing the
+    /// barycentric coordinates are used instead.
+    pub uvs: Option<[(f32, f32); 3]>,
+    pub material: Arc<dyn Material>
+}
+
+impl Shape for Triangle {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        let [v0, v1, v2] = &self.vertices;
+        let edge1 = v1 - v0;
+        let edge2 = v2 - v0;
+        let pvec = V3::cross(&ray.direction, &edge2);
+        let det = V3::dot(&edge1, &pvec);
+        if det.abs() < TRIANGLE_EPSILON {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let tvec = ray.origin - v0;
+        let b1 = V3::dot(&tvec, &pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qvec = V3::cross(&tvec, &edge1);
+        let b2 = V3::dot(&ray.direction, &qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = V3::dot(&edge2, &qvec) * inv_det;
+        if !t_range.contains(&t) {
+            return None;
+        }
+        let b0 = 1.0 - b1 - b2;
+        // the winding decides the side, even with smooth normals
+        let front_face = det > 0.0;
+        let normal = match &self.normals {
+            Some([n0, n1, n2]) => (b0*n0 + b1*n1 + b2*n2).unit(),
+            None => V3::cross(&edge1, &edge2).unit()
+        };
+        let normal = if front_face { normal } else { -normal };
+        let (u, v) = match &self.uvs {
+            Some([uv0, uv1, uv2]) => (
+                b0*uv0.0 + b1*uv1.0 + b2*uv2.0,
+                b0*uv0.1 + b1*uv1.1 + b2*uv2.1),
+            None => (b1, b2)
+        };
+        Some(HitRecord {
+            t,
+            p: ray.point_at_parameter(t),
+            normal, front_face, u, v,
+            material: &*self.material
+        })
+    }
+
+    fn bounding_box(&self, _t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        // axis-aligned triangles have a flat box, give it some thickness
+        Some(Aabb::from_points(&self.vertices).pad(RECT_THICKNESS))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_front_face() {
+        let range = 0.001..f32::MAX;
+        let ray = |x: f32, direction: f32| Ray {
+            origin: V3 {x, y: 0.0, z: 0.0},
+            direction: V3 {x: direction, y: 0.0, z: 0.0},
+            time: 0.0
+        };
+        let sphere = |radius| Sphere {
+            center: V3 {x: 0.0, y: 0.0, z: 0.0},
+            radius,
+            material: Box::new(Dielectric { ref_idx: 1.5 })
+        };
+        // from outside, then from the center
+        let solid = sphere(1.0);
+        let hit = solid.hit(&ray(-3.0, 1.0), &range).unwrap();
+        assert!(hit.front_face);
+        assert_eq!(V3 {x: -1.0, y: 0.0, z: 0.0}, hit.normal);
+        let hit = solid.hit(&ray(0.0, 1.0), &range).unwrap();
+        assert!(!hit.front_face);
+        assert_eq!(V3 {x: -1.0, y: 0.0, z: 0.0}, hit.normal);
+        // inside out: the same normals, the other faces
+        let inverted = sphere(-1.0);
+        assert!(!inverted.hit(&ray(-3.0, 1.0), &range).unwrap().front_face);
+        assert!(inverted.hit(&ray(0.0, 1.0), &range).unwrap().front_face);
+        let bbox = inverted.bounding_box(&range).unwrap();
+        assert_eq!((V3 {x: -1.0, y: -1.0, z: -1.0}, V3 {x: 1.0, y: 1.0, z: 1.0}), (bbox.min, bbox.max));
+        let (hit, inverted_hit) = (solid.hit(&ray(-3.0, 1.0), &range).unwrap(),
+                                   inverted.hit(&ray(-3.0, 1.0), &range).unwrap());
+        assert_eq!((hit.u, hit.v), (inverted_hit.u, inverted_hit.v));
+
+        // the back of a box's min face, from inside
+        let unit_box = BoxShape {
+            pmin: V3 {x: -1.0, y: -1.0, z: -1.0},
+            pmax: V3 {x: 1.0, y: 1.0, z: 1.0},
+            material: Box::new(Dielectric { ref_idx: 1.5 })
+        };
+        let hit = unit_box.hit(&ray(0.0, -1.0), &range).unwrap();
+        assert!(!hit.front_face);
+        assert_eq!(V3 {x: 1.0, y: 0.0, z: 0.0}, hit.normal);
+        assert!(unit_box.hit(&ray(3.0, -1.0), &range).unwrap().front_face);
+        let flipped = FlipNormals { shape: Box::new(unit_box) };
+        let hit = flipped.hit(&ray(0.0, -1.0), &range).unwrap();
+        assert!(hit.front_face);
+        assert_eq!(V3 {x: 1.0, y: 0.0, z: 0.0}, hit.normal);
+    }
+
+    fn toward(origin: V3, direction: V3) -> Ray {
+        Ray { origin, direction, time: 0.0 }
+    }
+
+    #[test]
+    fn test_xy_rect() {
+        let range = 0.001..f32::MAX;
+        let rect = XyRect {
+            x0: -1.0, x1: 3.0, y0: 0.0, y1: 2.0, k: -2.0,
+            material: Box::new(Dielectric { ref_idx: 1.5 })
+        };
+        let hit = rect.hit(&toward(V3 {x: 0.0, y: 1.5, z: 0.0}, V3 {x: 0.0, y: 0.0, z: -1.0}), &range)
+            .expect("should hit the rectangle");
+        assert_eq!(2.0, hit.t);
+        assert_eq!(V3 {x: 0.0, y: 1.5, z: -2.0}, hit.p);
+        assert_eq!(V3 {x: 0.0, y: 0.0, z: 1.0}, hit.normal);
+        assert!(hit.front_face);
+        assert_eq!((0.25, 0.75), (hit.u, hit.v));
+        // from behind, the normal still faces the ray
+        let hit = rect.hit(&toward(V3 {x: 0.0, y: 1.5, z: -4.0}, V3 {x: 0.0, y: 0.0, z: 1.0}), &range).unwrap();
+        assert_eq!(V3 {x: 0.0, y: 0.0, z: -1.0}, hit.normal);
+        assert!(!hit.front_face);
+        // the edges belong to the rectangle, just past them doesn't
+        let down = V3 {x: 0.0, y: 0.0, z: -1.0};
+        let hit = rect.hit(&toward(V3 {x: -1.0, y: 0.0, z: 0.0}, down), &range).unwrap();
+        assert_eq!((0.0, 0.0), (hit.u, hit.v));
+        let hit = rect.hit(&toward(V3 {x: 3.0, y: 2.0, z: 0.0}, down), &range).unwrap();
+        assert_eq!((1.0, 1.0), (hit.u, hit.v));
+        assert!(rect.hit(&toward(V3 {x: -1.01, y: 1.0, z: 0.0}, down), &range).is_none());
+        assert!(rect.hit(&toward(V3 {x: 0.0, y: 2.01, z: 0.0}, down), &range).is_none());
+        // out of the t range, going away, and parallel
+        assert!(rect.hit(&toward(V3 {x: 0.0, y: 1.0, z: 0.0}, down), &(0.001..1.5)).is_none());
+        assert!(rect.hit(&toward(V3 {x: 0.0, y: 1.0, z: 0.0}, -down), &range).is_none());
+        assert!(rect.hit(&toward(V3 {x: -5.0, y: 1.0, z: -2.0}, V3 {x: 1.0, y: 0.0, z: 0.0}), &range).is_none());
+        let bbox = rect.bounding_box(&range).unwrap();
+        assert_eq!((-1.0, 0.0, 3.0, 2.0), (bbox.min.x, bbox.min.y, bbox.max.x, bbox.max.y));
+        assert!(bbox.min.z < -2.0 && bbox.max.z > -2.0);
+    }
+
+    #[test]
+    fn test_xz_and_yz_rects() {
+        let range = 0.001..f32::MAX;
+        let xz = XzRect {
+            x0: 0.0, x1: 2.0, z0: 0.0, z1: 4.0, k: 1.0,
+            material: Box::new(Dielectric { ref_idx: 1.5 })
+        };
+        let hit = xz.hit(&toward(V3 {x: 0.5, y: 3.0, z: 1.0}, V3 {x: 0.0, y: -1.0, z: 0.0}), &range).unwrap();
+        assert_eq!(2.0, hit.t);
+        assert_eq!(V3 {x: 0.0, y: 1.0, z: 0.0}, hit.normal);
+        assert!(hit.front_face);
+        assert_eq!((0.25, 0.25), (hit.u, hit.v));
+        assert!(xz.hit(&toward(V3 {x: 0.5, y: 3.0, z: 4.5}, V3 {x: 0.0, y: -1.0, z: 0.0}), &range).is_none());
+        let bbox = xz.bounding_box(&range).unwrap();
+        assert!(bbox.min.y < 1.0 && bbox.max.y > 1.0);
+        assert_eq!((0.0, 4.0), (bbox.min.z, bbox.max.z));
+
+        let yz = YzRect {
+            y0: 0.0, y1: 1.0, z0: -1.0, z1: 1.0, k: 0.0,
+            material: Box::new(Dielectric { ref_idx: 1.5 })
+        };
+        let hit = yz.hit(&toward(V3 {x: -1.0, y: 0.5, z: 0.5}, V3 {x: 1.0, y: 0.0, z: 0.0}), &range).unwrap();
+        assert_eq!(1.0, hit.t);
+        assert_eq!(V3 {x: -1.0, y: 0.0, z: 0.0}, hit.normal);
+        assert!(!hit.front_face);
+        assert_eq!((0.5, 0.75), (hit.u, hit.v));
+        assert!(yz.hit(&toward(V3 {x: -1.0, y: -0.5, z: 0.5}, V3 {x: 1.0, y: 0.0, z: 0.0}), &range).is_none());
+        let bbox = yz.bounding_box(&range).unwrap();
+        assert!(bbox.min.x < 0.0 && bbox.max.x > 0.0);
+        assert_eq!((0.0, 1.0), (bbox.min.y, bbox.max.y));
+    }
+
+    #[test]
+    fn test_flip_normals() {
+        let range = 0.001..f32::MAX;
+        let rect = || Box::new(XyRect {
+            x0: 0.0, x1: 1.0, y0: 0.0, y1: 1.0, k: 0.0,
+            material: Box::new(Dielectric { ref_idx: 1.5 })
+        });
+        let (plain, flipped) = (rect(), FlipNormals { shape: rect() });
+        let ray = toward(V3 {x: 0.5, y: 0.5, z: 1.0}, V3 {x: 0.0, y: 0.0, z: -1.0});
+        let (hit, flipped_hit) = (plain.hit(&ray, &range).unwrap(), flipped.hit(&ray, &range).unwrap());
+        assert!(hit.front_face && !flipped_hit.front_face);
+        // only the side changes, not where or what was hit
+        assert_eq!((hit.t, hit.p, hit.normal), (flipped_hit.t, flipped_hit.p, flipped_hit.normal));
+        assert_eq!((hit.u, hit.v), (flipped_hit.u, flipped_hit.v));
+        assert!(flipped.hit(&toward(V3 {x: 1.5, y: 0.5, z: 1.0}, V3 {x: 0.0, y: 0.0, z: -1.0}), &range).is_none());
+        let (bbox, flipped_bbox) = (plain.bounding_box(&range).unwrap(), flipped.bounding_box(&range).unwrap());
+        assert_eq!((bbox.min, bbox.max), (flipped_bbox.min, flipped_bbox.max));
+    }
+
+    #[test]
+    fn test_box() {
+        let range = 0.001..f32::MAX;
+        let cuboid = BoxShape {
+            pmin: V3 {x: 0.0, y: 0.0, z: 0.0},
+            pmax: V3 {x: 1.0, y: 2.0, z: 4.0},
+            material: Box::new(Dielectric { ref_idx: 1.5 })
+        };
+        // one ray per face from outside: the nearest face wins,
+        // and its normal points out of the box
+        let cases = [
+            (V3 {x: -1.0, y: 1.0, z: 2.0}, V3 {x: 1.0, y: 0.0, z: 0.0}, 1.0, V3 {x: -1.0, y: 0.0, z: 0.0}),
+            (V3 {x: 3.0, y: 1.0, z: 2.0}, V3 {x: -1.0, y: 0.0, z: 0.0}, 2.0, V3 {x: 1.0, y: 0.0, z: 0.0}),
+            (V3 {x: 0.5, y: -3.0, z: 2.0}, V3 {x: 0.0, y: 1.0, z: 0.0}, 3.0, V3 {x: 0.0, y: -1.0, z: 0.0}),
+            (V3 {x: 0.5, y: 3.0, z: 2.0}, V3 {x: 0.0, y: -1.0, z: 0.0}, 1.0, V3 {x: 0.0, y: 1.0, z: 0.0}),
+            (V3 {x: 0.5, y: 1.0, z: -2.0}, V3 {x: 0.0, y: 0.0, z: 1.0}, 2.0, V3 {x: 0.0, y: 0.0, z: -1.0}),
+            (V3 {x: 0.5, y: 1.0, z: 5.0}, V3 {x: 0.0, y: 0.0, z: -1.0}, 1.0, V3 {x: 0.0, y: 0.0, z: 1.0})
+        ];
+        for &(origin, direction, t, normal) in &cases {
+            let hit = cuboid.hit(&toward(origin, direction), &range).expect("should hit the box");
+            assert_eq!(t, hit.t);
+            assert_eq!(normal, hit.normal);
+            assert!(hit.front_face);
+        }
+        // diagonally, the near face is hit even though the far
+        // faces come later in the loop
+        let hit = cuboid.hit(&toward(V3 {x: 0.5, y: 1.0, z: 8.0}, V3 {x: 0.0, y: 0.1, z: -1.0}), &range).unwrap();
+        assert_eq!(V3 {x: 0.0, y: 0.0, z: 1.0}, hit.normal);
+        assert!((hit.t - 4.0).abs() < 1e-5);
+        // the far face, once the near one is out of the t range
+        let hit = cuboid.hit(&toward(V3 {x: 0.5, y: 1.0, z: 5.0}, V3 {x: 0.0, y: 0.0, z: -1.0}), &(1.5..f32::MAX)).unwrap();
+        assert_eq!(5.0, hit.t);
+        assert!(!hit.front_face);
+        // beside the box
+        assert!(cuboid.hit(&toward(V3 {x: 1.5, y: 1.0, z: 5.0}, V3 {x: 0.0, y: 0.0, z: -1.0}), &range).is_none());
+        let bbox = cuboid.bounding_box(&range).unwrap();
+        assert_eq!((cuboid.pmin, cuboid.pmax), (bbox.min, bbox.max));
+    }
+
+    #[test]
+    fn test_sphere_uv() {
+        let uv = |x, y, z| sphere_uv(&V3 {x, y, z});
+        let close = |(u, v): (f32, f32), (eu, ev): (f32, f32)| (u - eu).abs() < 1e-5 && (v - ev).abs() < 1e-5;
+        assert!(close(uv(1.0, 0.0, 0.0), (0.5, 0.5)));
+        // a quarter of the way around from -x towards +z,
+        // three quarters on the way back through -z
+        assert!(close(uv(0.0, 0.0, 1.0), (0.25, 0.5)));
+        assert!(close(uv(0.0, 0.0, -1.0), (0.75, 0.5)));
+        // the seam at -x: u wraps around from 1 to 0
+        assert!(close(uv(-1.0, 0.0, 0.0), (0.0, 0.5)));
+        assert!(uv(-1.0, 0.0, 0.001).0 < 0.001);
+        assert!(uv(-1.0, 0.0, -0.001).0 > 0.999);
+        // the poles, even slightly off the unit sphere
+        assert_eq!(1.0, uv(0.0, 1.0, 0.0).1);
+        assert_eq!(0.0, uv(0.0, -1.0, 0.0).1);
+        assert_eq!(1.0, uv(0.0, 1.000001, 0.0).1);
+        assert_eq!(0.0, uv(0.0, -1.000001, 0.0).1);
+        for &(x, y, z) in &[(0.0, 1.0, 0.0), (0.0, -1.0, 0.0)] {
+            let u = uv(x, y, z).0;
+            assert!((0.0..=1.0).contains(&u));
+        }
+    }
+
+    fn test_triangle(vertices: [V3; 3]) -> Triangle {
+        Triangle {
+            vertices,
+            normals: None,
+            uvs: Some([(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]),
+            material: Arc::new(Dielectric { ref_idx: 1.5 })
+        }
+    }
+
+    fn xy_triangle() -> Triangle {
+        test_triangle([
+            V3 {x: 0.0, y: 0.0, z: 0.0},
+            V3 {x: 1.0, y: 0.0, z: 0.0},
+            V3 {x: 0.0, y: 1.0, z: 0.0}
+        ])
+    }
+
+    #[test]
+    fn test_triangle_hit() {
+        let triangle = xy_triangle();
+        let hit = triangle.hit(&Ray {
+            origin: V3 {x: 0.25, y: 0.5, z: 2.0},
+            direction: V3 {x: 0.0, y: 0.0, z: -1.0},
+            time: 0.0
+        }, &(0.001..f32::MAX)).expect("should hit the triangle");
+        assert_eq!(2.0, hit.t);
+        assert_eq!(V3 {x: 0.0, y: 0.0, z: 1.0}, hit.normal);
+        assert!(hit.front_face);
+        assert_eq!((0.25, 0.5), (hit.u, hit.v));
+        // from behind
+        let hit = triangle.hit(&Ray {
+            origin: V3 {x: 0.25, y: 0.5, z: -2.0},
+            direction: V3 {x: 0.0, y: 0.0, z: 1.0},
+            time: 0.0
+        }, &(0.001..f32::MAX)).expect("should hit the triangle");
+        assert_eq!(V3 {x: 0.0, y: 0.0, z: -1.0}, hit.normal);
+        assert!(!hit.front_face);
+    }
+
+    #[test]
+    fn test_triangle_miss() {
+        let triangle = xy_triangle();
+        let range = 0.001..f32::MAX;
+        // outside of the hypotenuse
+        assert!(triangle.hit(&Ray {
+            origin: V3 {x: 0.6, y: 0.6, z: 2.0},
+            direction: V3 {x: 0.0, y: 0.0, z: -1.0},
+            time: 0.0
+        }, &range).is_none());
+        // parallel to the triangle
+        assert!(triangle.hit(&Ray {
+            origin: V3 {x: -1.0, y: 0.1, z: 0.0},
+            direction: V3 {x: 1.0, y: 0.0, z: 0.0},
+            time: 0.0
+        }, &range).is_none());
+    }
+
+    #[test]
+    fn test_axis_aligned_triangle_bounding_box() {
+        let range = 0.001..f32::MAX;
+        let bbox = xy_triangle().bounding_box(&range).unwrap();
+        assert!(bbox.max.z > bbox.min.z);
+        let ray = Ray {
+            origin: V3 {x: 0.25, y: 0.25, z: 2.0},
+            direction: V3 {x: 0.0, y: 0.0, z: -1.0},
+            time: 0.0
+        };
+        assert!(bbox.hit(&ray.origin, &ray.inverse_direction(), range.start, range.end));
+
+        // degenerate triangle, all vertices on a line along x
+        let bbox = test_triangle([
+            V3 {x: 0.0, y: 0.0, z: 0.0},
+            V3 {x: 1.0, y: 0.0, z: 0.0},
+            V3 {x: 2.0, y: 0.0, z: 0.0}
+        ]).bounding_box(&range).unwrap();
+        assert!(bbox.max.y > bbox.min.y && bbox.max.z > bbox.min.z);
+        let ray = Ray {
+            origin: V3 {x: 0.5, y: 0.0, z: 2.0},
+            direction: V3 {x: 0.0, y: 0.0, z: -1.0},
+            time: 0.0
+        };
+        assert!(bbox.hit(&ray.origin, &ray.inverse_direction(), range.start, range.end));
+    }
+
+    #[test]
+    fn test_infinite_plane() {
+        let plane = InfinitePlane {
+            point: V3 {x: 0.0, y: -1.0, z: 0.0},
+            normal: V3 {x: 0.0, y: 2.0, z: 0.0},
+            material: Box::new(Dielectric { ref_idx: 1.5 })
+        };
+        assert!(plane.bounding_box(&(0.0..1.0)).is_none());
+        let hit = plane.hit(&Ray {
+            origin: V3 {x: 100.0, y: 1.0, z: -50.0},
+            direction: V3 {x: 1.0, y: -1.0, z: 0.0},
+            time: 0.0
+        }, &(0.001..f32::MAX)).expect("should hit the plane");
+        assert_eq!(2.0, hit.t);
+        assert_eq!(V3 {x: 0.0, y: 1.0, z: 0.0}, hit.normal);
+        assert_eq!(V3 {x: 102.0, y: -1.0, z: -50.0}, hit.p);
+        // parallel to the plane, and going away from it
+        for direction in &[V3 {x: 1.0, y: 0.0, z: 0.0}, V3 {x: 0.0, y: 1.0, z: 0.0}] {
+            assert!(plane.hit(&Ray {
+                origin: V3 {x: 0.0, y: 1.0, z: 0.0},
+                direction: *direction,
+                time: 0.0
+            }, &(0.001..f32::MAX)).is_none());
+        }
+    }
+}
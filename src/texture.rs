@@ -1,7 +1,24 @@
-use crate::v3color::*;
+use crate::{v3color::*, image::*, transform::*};
 
-pub trait Texture: Sync {
-    fn value(&self, p: &V3) -> Color;
+use std::sync::Arc;
+
+pub trait Texture: Send + Sync {
+    /// `u` and `v` are the surface coordinates of the hit, `p` its position
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color;
+}
+
+/// shared textures, for instance one named in a scene file
+/// and used by several materials
+impl<T: Texture + ?Sized> Texture for Arc<T> {
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        (**self).value(u, v, p)
+    }
+}
+
+/// a gray level, clamped to 0..1
+pub fn gray(level: f32) -> Color {
+    let level = level.clamp(0.0, 1.0);
+    Color { r: level, g: level, b: level }
 }
 
 pub struct ConstantTexture {
@@ -9,25 +26,29 @@ pub struct ConstantTexture {
 }
 
 impl Texture for ConstantTexture {
-    fn value(&self, _p: &V3) -> Color {
+    fn value(&self, _u: f32, _v: f32, _p: &V3) -> Color {
         self.color
     }
 }
 
+/// checks in space, π/frequency wide
 pub struct CheckerTexture {
-    pub odd: Box<Texture>,
-    pub even: Box<Texture>
+    pub odd: Box<dyn Texture>,
+    pub even: Box<dyn Texture>,
+    pub frequency: f32
 }
 
 impl Texture for CheckerTexture {
-    fn value(&self, p: &V3) -> Color {
-        let sines = f32::sin(10.0*p.x)
-            * f32::sin(10.0*p.y)
-            * f32::sin(10.0*p.z);
-        if sines < 0.0 {
-            self.odd.value(p)
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        // the cells are as wide as the half periods of sin(frequency * x).
+        // Rounding errors put the hits on a surface lying on a cell boundary,
+        // the ground at y = 0 for instance, a hair on either side of it,
+        // so a little under the boundary counts as on it
+        let cell = |x: f32| (self.frequency * x / std::f32::consts::PI + 1e-4).floor() as i64;
+        if (cell(p.x) + cell(p.y) + cell(p.z)).rem_euclid(2) == 1 {
+            self.odd.value(u, v, p)
         } else {
-            self.even.value(p)
+            self.even.value(u, v, p)
         }
     }
 }
@@ -35,19 +56,274 @@ impl Texture for CheckerTexture {
 /// that one's mine, not from the book,
 /// so take it with a bucketful of salt
 pub struct SphericalCheckerTexture {
-    pub odd: Box<Texture>,
-    pub even: Box<Texture>
+    pub odd: Box<dyn Texture>,
+    pub even: Box<dyn Texture>,
+    pub frequency: f32
 }
 
 impl Texture for SphericalCheckerTexture {
-    fn value(&self, p: &V3) -> Color {
-        let modulate = |v: f32| f32::sin(10.0*v);
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        let modulate = |v: f32| f32::sin(self.frequency*v);
         let sines = modulate(p.y.atan2(p.x))
             * modulate(p.z.atan2(p.x));
         if sines < 0.0 {
-            self.odd.value(p)
+            self.odd.value(u, v, p)
         } else {
-            self.even.value(p)
+            self.even.value(u, v, p)
+        }
+    }
+}
+
+/// checks in surface coordinates, so they follow the shape
+pub struct UvCheckerTexture {
+    pub odd: Box<dyn Texture>,
+    pub even: Box<dyn Texture>,
+    /// how many squares across u and v go from 0 to 1
+    pub squares_u: f32,
+    pub squares_v: f32
+}
+
+impl Texture for UvCheckerTexture {
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        let cells = (u * self.squares_u).floor() as i64 + (v * self.squares_v).floor() as i64;
+        if cells.rem_euclid(2) == 1 {
+            self.odd.value(u, v, p)
+        } else {
+            self.even.value(u, v, p)
+        }
+    }
+}
+
+/// `a` where the amount is black, `b` where it's white,
+/// blended by its luminance in between
+pub struct MixTexture {
+    pub a: Box<dyn Texture>,
+    pub b: Box<dyn Texture>,
+    pub amount: Box<dyn Texture>
+}
+
+impl Texture for MixTexture {
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        let t = luminance(&self.amount.value(u, v, p)).clamp(0.0, 1.0);
+        ((1.0 - t) * self.a.value(u, v, p).to_v3() + t * self.b.value(u, v, p).to_v3()).to_color()
+    }
+}
+
+/// channel by channel
+pub struct MultiplyTexture {
+    pub a: Box<dyn Texture>,
+    pub b: Box<dyn Texture>
+}
+
+impl Texture for MultiplyTexture {
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        (self.a.value(u, v, p).to_v3() * self.b.value(u, v, p).to_v3()).to_color()
+    }
+}
+
+pub struct AddTexture {
+    pub a: Box<dyn Texture>,
+    pub b: Box<dyn Texture>
+}
+
+impl Texture for AddTexture {
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        (self.a.value(u, v, p).to_v3() + self.b.value(u, v, p).to_v3()).to_color()
+    }
+}
+
+/// 1 minus each channel, for textures in 0..1
+pub struct InvertTexture {
+    pub input: Box<dyn Texture>
+}
+
+impl Texture for InvertTexture {
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        let c = self.input.value(u, v, p);
+        Color { r: 1.0 - c.r, g: 1.0 - c.g, b: 1.0 - c.b }
+    }
+}
+
+/// moves a solid texture around: the pattern goes through the
+/// transform, so the positions go through its inverse
+pub struct TransformedTexture {
+    input: Box<dyn Texture>,
+    inverse: M4
+}
+
+impl TransformedTexture {
+    /// panics if the transform can't be inverted
+    pub fn new(input: Box<dyn Texture>, transform: M4) -> TransformedTexture {
+        TransformedTexture { input, inverse: transform.inverse().expect("the transform can't be inverted") }
+    }
+}
+
+impl Texture for TransformedTexture {
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        self.input.value(u, v, &self.inverse.transform_point(p))
+    }
+}
+
+/// what happens to the coordinates outside of 0..1
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WrapMode {
+    /// the image tiles
+    Repeat,
+    /// the edge pixels go on
+    Clamp
+}
+
+impl WrapMode {
+    pub fn from_name(name: &str) -> Option<WrapMode> {
+        match name {
+            "repeat" => Some(WrapMode::Repeat),
+            "clamp" => Some(WrapMode::Clamp),
+            _ => None
         }
     }
-}
\ No newline at end of file
+
+    fn index(self, i: i64, size: usize) -> usize {
+        match self {
+            WrapMode::Repeat => i.rem_euclid(size as i64) as usize,
+            WrapMode::Clamp => i.clamp(0, size as i64 - 1) as usize
+        }
+    }
+}
+
+/// an image stretched over the surface coordinates,
+/// with v = 0 at the bottom of the image
+pub struct ImageTexture {
+    pub image: Arc<Image>,
+    pub wrap: WrapMode
+}
+
+impl Texture for ImageTexture {
+    /// bilinear filtering between the four closest pixel centers
+    fn value(&self, u: f32, v: f32, _p: &V3) -> Color {
+        let (width, height) = (self.image.width, self.image.height);
+        let x = u * width as f32 - 0.5;
+        let y = (1.0 - v) * height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let pixel = |dx: i64, dy: i64| self.image.pixel(
+            self.wrap.index(x0 as i64 + dx, width),
+            self.wrap.index(y0 as i64 + dy, height)).to_v3();
+        let top = (1.0 - fx) * pixel(0, 0) + fx * pixel(1, 0);
+        let bottom = (1.0 - fx) * pixel(0, 1) + fx * pixel(1, 1);
+        ((1.0 - fy) * top + fy * bottom).to_color()
+    }
+}
+
+/// colors at positions along 0..1, blended linearly in between
+pub struct ColorRamp {
+    stops: Vec<(f32, Color)>
+}
+
+impl ColorRamp {
+    /// needs at least one stop, in any order
+    pub fn new(mut stops: Vec<(f32, Color)>) -> ColorRamp {
+        assert!(!stops.is_empty());
+        stops.sort_by(|a, b| f32_cmp(a.0, b.0));
+        ColorRamp { stops }
+    }
+
+    /// the first and last colors go on before and after the stops
+    pub fn color_at(&self, t: f32) -> Color {
+        let next = self.stops.iter().position(|stop| stop.0 > t);
+        match next {
+            Some(0) => self.stops[0].1,
+            None => self.stops[self.stops.len() - 1].1,
+            Some(i) => {
+                let ((t0, c0), (t1, c1)) = (self.stops[i - 1], self.stops[i]);
+                let f = (t - t0) / (t1 - t0);
+                ((1.0 - f) * c0.to_v3() + f * c1.to_v3()).to_color()
+            }
+        }
+    }
+}
+
+/// colors another texture, usually a gray one, by its luminance
+pub struct RampTexture {
+    pub input: Box<dyn Texture>,
+    pub ramp: ColorRamp
+}
+
+impl Texture for RampTexture {
+    fn value(&self, u: f32, v: f32, p: &V3) -> Color {
+        self.ramp.color_at(luminance(&self.input.value(u, v, p)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_image_texture() {
+        let gray = |l: f32| Color { r: l, g: l, b: l };
+        // 0 1
+        // 2 3
+        let image = Arc::new(Image { width: 2, height: 2, pixels: vec![gray(0.0), gray(1.0), gray(2.0), gray(3.0)] });
+        let origin = V3 { x: 0.0, y: 0.0, z: 0.0 };
+        let repeat = ImageTexture { image: image.clone(), wrap: WrapMode::Repeat };
+        let clamp = ImageTexture { image, wrap: WrapMode::Clamp };
+        // pixel centers
+        assert_eq!(gray(2.0), repeat.value(0.25, 0.25, &origin));
+        assert_eq!(gray(1.0), repeat.value(0.75, 0.75, &origin));
+        // halfway between all four
+        assert_eq!(gray(1.5), clamp.value(0.5, 0.5, &origin));
+        // at the corner, repeat blends the four corners, clamp doesn't
+        assert_eq!(gray(1.5), repeat.value(0.0, 1.0, &origin));
+        assert_eq!(gray(0.0), clamp.value(0.0, 1.0, &origin));
+        assert_eq!(gray(3.0), clamp.value(7.0, -3.0, &origin));
+        assert!((repeat.value(0.3, 0.6, &origin).r - repeat.value(2.3, -1.4, &origin).r).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_color_ramp() {
+        let gray = |l: f32| Color { r: l, g: l, b: l };
+        let ramp = ColorRamp::new(vec![(0.8, gray(1.0)), (0.2, gray(0.0)), (0.6, gray(0.2))]);
+        assert_eq!(gray(0.0), ramp.color_at(-1.0));
+        assert_eq!(gray(0.0), ramp.color_at(0.2));
+        assert!((ramp.color_at(0.4).r - 0.1).abs() < 1e-6);
+        assert!((ramp.color_at(0.7).r - 0.6).abs() < 1e-6);
+        assert_eq!(gray(1.0), ramp.color_at(0.8));
+        assert_eq!(gray(1.0), ramp.color_at(f32::INFINITY));
+        let texture = RampTexture { input: Box::new(ConstantTexture { color: gray(0.4) }), ramp };
+        assert!((texture.value(0.0, 0.0, &V3 { x: 0.0, y: 0.0, z: 0.0 }).g - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_combinators() {
+        let constant = |l: f32| Box::new(ConstantTexture { color: gray(l) });
+        let origin = V3 { x: 0.0, y: 0.0, z: 0.0 };
+        let mix = MixTexture { a: constant(0.2), b: constant(1.0), amount: constant(0.25) };
+        assert!((mix.value(0.0, 0.0, &origin).r - 0.4).abs() < 1e-6);
+        assert_eq!(gray(0.5), MultiplyTexture { a: constant(0.5), b: constant(1.0) }.value(0.0, 0.0, &origin));
+        assert_eq!(gray(0.75), AddTexture { a: constant(0.5), b: constant(0.25) }.value(0.0, 0.0, &origin));
+        assert_eq!(gray(0.75), InvertTexture { input: constant(0.25) }.value(0.0, 0.0, &origin));
+
+        let uv_checker = UvCheckerTexture { odd: constant(1.0), even: constant(0.0), squares_u: 4.0, squares_v: 2.0 };
+        assert_eq!(gray(0.0), uv_checker.value(0.1, 0.1, &origin));
+        assert_eq!(gray(1.0), uv_checker.value(0.3, 0.1, &origin));
+        assert_eq!(gray(0.0), uv_checker.value(0.3, 0.6, &origin));
+        // negative coordinates go on alternating
+        assert_eq!(gray(1.0), uv_checker.value(-0.1, 0.1, &origin));
+
+        // a checker twice as large, moved by one unit along x
+        let checker = CheckerTexture { odd: constant(1.0), even: constant(0.0), frequency: 1.0 };
+        let transformed = TransformedTexture::new(Box::new(checker),
+            M4::translation(&V3 { x: 1.0, y: 0.0, z: 0.0 }) * M4::scaling(&V3 { x: 2.0, y: 2.0, z: 2.0 }));
+        let p = V3 { x: 4.0, y: 1.0, z: 1.0 };
+        // (1.5, 0.5, 0.5) in the checker, where all the sines are positive, rather than sin(4)
+        assert_eq!(gray(0.0), transformed.value(0.0, 0.0, &p));
+        assert_eq!(gray(1.0), transformed.value(0.0, 0.0, &V3 { x: 4.0, y: 1.0, z: -1.0 }));
+
+        // no speckles on a plane through the boundary at y = 0
+        let checker = CheckerTexture { odd: constant(1.0), even: constant(0.0), frequency: 1.0 };
+        for &y in &[-1e-6, 0.0, 1e-6] {
+            assert_eq!(gray(0.0), checker.value(0.0, 0.0, &V3 { x: 0.5, y, z: 0.5 }));
+            assert_eq!(gray(1.0), checker.value(0.0, 0.0, &V3 { x: 3.5, y, z: 0.5 }));
+        }
+    }
+}
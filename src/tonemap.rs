@@ -0,0 +1,105 @@
+// display transform: from scene radiance to the 0..1 range of 8-bit images
+
+use crate::v3color::*;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ToneMapper {
+    /// values above 1 are simply cut
+    Clamp,
+    /// x / (1 + x)
+    Reinhard,
+    /// Reinhard, but reaching 1 at the white point instead of at infinity
+    ExtendedReinhard,
+    /// John Hable's filmic curve from Uncharted 2
+    Hable,
+    /// Krzysztof Narkowicz's fit of the ACES reference rendering transform
+    Aces
+}
+
+impl ToneMapper {
+    pub fn from_name(name: &str) -> Option<ToneMapper> {
+        match name {
+            "clamp" => Some(ToneMapper::Clamp),
+            "reinhard" => Some(ToneMapper::Reinhard),
+            "extended-reinhard" => Some(ToneMapper::ExtendedReinhard),
+            "hable" => Some(ToneMapper::Hable),
+            "aces" => Some(ToneMapper::Aces),
+            _ => None
+        }
+    }
+}
+
+fn hable_partial(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x*(a*x + c*b) + d*e) / (x*(a*x + b) + d*f)) - e/f
+}
+
+pub struct DisplayTransform {
+    pub tone_mapper: ToneMapper,
+    /// in stops: each one doubles the brightness
+    pub exposure: f32,
+    /// the radiance mapped to 1 by the extended Reinhard and Hable operators
+    pub white_point: f32
+}
+
+impl Default for DisplayTransform {
+    fn default() -> DisplayTransform {
+        DisplayTransform { tone_mapper: ToneMapper::Clamp, exposure: 0.0, white_point: 4.0 }
+    }
+}
+
+impl DisplayTransform {
+    fn tone_map(&self, x: f32) -> f32 {
+        let w = self.white_point;
+        let mapped = match self.tone_mapper {
+            ToneMapper::Clamp => x,
+            ToneMapper::Reinhard => x / (1.0 + x),
+            ToneMapper::ExtendedReinhard => x * (1.0 + x/(w*w)) / (1.0 + x),
+            ToneMapper::Hable => hable_partial(x) / hable_partial(w),
+            ToneMapper::Aces => (x*(2.51*x + 0.03)) / (x*(2.43*x + 0.59) + 0.14)
+        };
+        mapped.clamp(0.0, 1.0)
+    }
+
+    /// linear radiance to sRGB encoded values in 0..1
+    pub fn apply(&self, c: &Color) -> Color {
+        let scale = 2f32.powf(self.exposure);
+        // NaN would go through the clamp
+        let encode = |x: f32| if x.is_nan() { 0.0 } else {
+            linear_to_srgb(self.tone_map(x.max(0.0) * scale))
+        };
+        Color { r: encode(c.r), g: encode(c.g), b: encode(c.b) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tone_mappers() {
+        for &tone_mapper in &[ToneMapper::Clamp, ToneMapper::Reinhard, ToneMapper::ExtendedReinhard,
+                              ToneMapper::Hable, ToneMapper::Aces] {
+            let display = DisplayTransform { tone_mapper, ..Default::default() };
+            let values: Vec<f32> = [0.0, 0.01, 0.1, 0.5, 1.0, 2.0, 4.0, 100.0].iter()
+                .map(|&x| display.tone_map(x)).collect();
+            assert!(values[0].abs() < 0.01, "{:?} {:?}", tone_mapper, values);
+            assert!(values.windows(2).all(|w| w[0] <= w[1]), "{:?} {:?}", tone_mapper, values);
+            assert!(values[7] <= 1.0);
+        }
+        let display = DisplayTransform { tone_mapper: ToneMapper::ExtendedReinhard, ..Default::default() };
+        assert!((display.tone_map(display.white_point) - 1.0).abs() < 1e-6);
+        let display = DisplayTransform { tone_mapper: ToneMapper::Hable, ..Default::default() };
+        assert!((display.tone_map(display.white_point) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_exposure_and_oetf() {
+        let display = DisplayTransform { exposure: -1.0, ..Default::default() };
+        let c = display.apply(&Color { r: 1.0, g: 0.0, b: 2.0 });
+        assert!((c.r - 0.735_356_7).abs() < 1e-5);
+        assert_eq!(0.0, c.g);
+        assert!((c.b - 1.0).abs() < 1e-6);
+        assert!((srgb_to_linear(linear_to_srgb(0.2)) - 0.2).abs() < 1e-6);
+    }
+}
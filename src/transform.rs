@@ -0,0 +1,228 @@
+// affine transforms, to place shapes in the world
+
+use crate::{v3color::*, shapes::*, bvh::*};
+
+use std::ops;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// 4x4 matrix, row-major, applied to column vectors
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct M4 {
+    pub m: [[f32; 4]; 4]
+}
+
+impl_op_ex!(* |a: &M4, b: &M4| -> M4 {
+    let mut m = [[0.0; 4]; 4];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, cell) in row.iter_mut().enumerate() {
+            *cell = (0..4).map(|k| a.m[i][k] * b.m[k][j]).sum();
+        }
+    }
+    M4 { m }
+});
+
+impl M4 {
+    pub fn identity() -> M4 {
+        M4 { m: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0]
+        ]}
+    }
+
+    pub fn translation(offset: &V3) -> M4 {
+        M4 { m: [
+            [1.0, 0.0, 0.0, offset.x],
+            [0.0, 1.0, 0.0, offset.y],
+            [0.0, 0.0, 1.0, offset.z],
+            [0.0, 0.0, 0.0, 1.0]
+        ]}
+    }
+
+    pub fn scaling(factors: &V3) -> M4 {
+        M4 { m: [
+            [factors.x, 0.0, 0.0, 0.0],
+            [0.0, factors.y, 0.0, 0.0],
+            [0.0, 0.0, factors.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0]
+        ]}
+    }
+
+    /// counter-clockwise rotation around `axis` when looking
+    /// from the tip of the axis towards the origin
+    pub fn rotation(axis: &V3, degrees: f32) -> M4 {
+        let a = axis.unit();
+        let theta = degrees*PI/180.0;
+        let (s, c) = (theta.sin(), theta.cos());
+        let t = 1.0 - c;
+        M4 { m: [
+            [t*a.x*a.x + c,     t*a.x*a.y - s*a.z, t*a.x*a.z + s*a.y, 0.0],
+            [t*a.x*a.y + s*a.z, t*a.y*a.y + c,     t*a.y*a.z - s*a.x, 0.0],
+            [t*a.x*a.z - s*a.y, t*a.y*a.z + s*a.x, t*a.z*a.z + c,     0.0],
+            [0.0, 0.0, 0.0, 1.0]
+        ]}
+    }
+
+    pub fn transpose(&self) -> M4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, cell) in row.iter_mut().enumerate() {
+                *cell = self.m[j][i];
+            }
+        }
+        M4 { m }
+    }
+
+    /// Gauss-Jordan elimination with partial pivoting.
+    /// None if the matrix is singular (for instance a zero scale).
+    pub fn inverse(&self) -> Option<M4> {
+        let mut a = self.m;
+        let mut inv = M4::identity().m;
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&i, &j| f32_cmp(a[i][col].abs(), a[j][col].abs()))?;
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+            let factor = 1.0 / a[col][col];
+            for k in 0..4 {
+                a[col][k] *= factor;
+                inv[col][k] *= factor;
+            }
+            for row in 0..4 {
+                if row != col {
+                    let f = a[row][col];
+                    for k in 0..4 {
+                        a[row][k] -= f * a[col][k];
+                        inv[row][k] -= f * inv[col][k];
+                    }
+                }
+            }
+        }
+        Some(M4 { m: inv })
+    }
+
+    pub fn transform_point(&self, p: &V3) -> V3 {
+        let m = &self.m;
+        V3 {
+            x: m[0][0]*p.x + m[0][1]*p.y + m[0][2]*p.z + m[0][3],
+            y: m[1][0]*p.x + m[1][1]*p.y + m[1][2]*p.z + m[1][3],
+            z: m[2][0]*p.x + m[2][1]*p.y + m[2][2]*p.z + m[2][3]
+        }
+    }
+
+    /// like a point, but ignoring the translation
+    pub fn transform_vector(&self, v: &V3) -> V3 {
+        let m = &self.m;
+        V3 {
+            x: m[0][0]*v.x + m[0][1]*v.y + m[0][2]*v.z,
+            y: m[1][0]*v.x + m[1][1]*v.y + m[1][2]*v.z,
+            z: m[2][0]*v.x + m[2][1]*v.y + m[2][2]*v.z
+        }
+    }
+}
+
+/// places a shape in the world. The shape is shared, so that
+/// the same geometry can be instanced many times.
+pub struct Transformed {
+    shape: Arc<dyn Shape>,
+    transform: M4,
+    inverse: M4,
+    /// normals must go through the inverse transpose
+    normal_transform: M4
+}
+
+impl Transformed {
+    /// panics if the transform can't be inverted
+    pub fn new(shape: Arc<dyn Shape>, transform: M4) -> Transformed {
+        let inverse = transform.inverse().expect("the transform matrix is not invertible");
+        Transformed {
+            shape, transform, inverse,
+            normal_transform: inverse.transpose()
+        }
+    }
+}
+
+impl Shape for Transformed {
+    fn hit<'a>(&'a self, ray: &Ray, t_range: &std::ops::Range<f32>) -> Option<HitRecord<'a>> {
+        // the direction isn't normalized, so t is the same in both spaces
+        let object_ray = Ray {
+            origin: self.inverse.transform_point(&ray.origin),
+            direction: self.inverse.transform_vector(&ray.direction),
+            time: ray.time
+        };
+        self.shape.hit(&object_ray, t_range).map(|h| HitRecord {
+            p: self.transform.transform_point(&h.p),
+            // still faces the ray: the inverse transpose keeps the sign of the dot product
+            normal: self.normal_transform.transform_vector(&h.normal).unit(),
+            ..h
+        })
+    }
+
+    fn bounding_box(&self, t_range: &std::ops::Range<f32>) -> Option<Aabb> {
+        let bbox = self.shape.bounding_box(t_range)?;
+        let corners: Vec<V3> = (0..8).map(|i| self.transform.transform_point(&V3 {
+            x: if i & 1 == 0 { bbox.min.x } else { bbox.max.x },
+            y: if i & 2 == 0 { bbox.min.y } else { bbox.max.y },
+            z: if i & 4 == 0 { bbox.min.z } else { bbox.max.z }
+        })).collect();
+        Some(Aabb::from_points(&corners))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material::*;
+
+    fn assert_close(a: &V3, b: &V3) {
+        assert!((a - b).length() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_inverse() {
+        let m = M4::translation(&V3 {x: 1.0, y: 2.0, z: 3.0})
+            * M4::rotation(&V3 {x: 1.0, y: 1.0, z: 0.0}, 30.0)
+            * M4::scaling(&V3 {x: 2.0, y: 0.5, z: 1.0});
+        let p = V3 {x: 0.3, y: -2.0, z: 5.0};
+        assert_close(&p, &m.inverse().unwrap().transform_point(&m.transform_point(&p)));
+        assert!(M4::scaling(&V3 {x: 1.0, y: 0.0, z: 1.0}).inverse().is_none());
+    }
+
+    #[test]
+    fn test_rotation() {
+        // a quarter turn around y brings x to -z
+        assert_close(&V3 {x: 0.0, y: 0.0, z: -1.0},
+                     &M4::rotation(&V3 {x: 0.0, y: 1.0, z: 0.0}, 90.0)
+                         .transform_vector(&V3 {x: 1.0, y: 0.0, z: 0.0}));
+    }
+
+    #[test]
+    fn test_transformed_hit() {
+        let unit_box: Arc<dyn Shape> = Arc::new(BoxShape {
+            pmin: V3 {x: -1.0, y: -1.0, z: -1.0},
+            pmax: V3 {x: 1.0, y: 1.0, z: 1.0},
+            material: Box::new(Dielectric { ref_idx: 1.5 })
+        });
+        // squashed along x, then rotated so that x becomes -z
+        let shape = Transformed::new(unit_box,
+            M4::translation(&V3 {x: 0.0, y: 0.0, z: -5.0})
+                * M4::rotation(&V3 {x: 0.0, y: 1.0, z: 0.0}, 90.0)
+                * M4::scaling(&V3 {x: 0.5, y: 1.0, z: 1.0}));
+        let hit = shape.hit(&Ray {
+            origin: V3 {x: 0.0, y: 0.0, z: 0.0},
+            direction: V3 {x: 0.0, y: 0.0, z: -2.0},
+            time: 0.0
+        }, &(0.001..f32::MAX)).expect("should hit the box");
+        assert!((hit.t - 2.25).abs() < 1e-5);
+        assert_close(&V3 {x: 0.0, y: 0.0, z: -4.5}, &hit.p);
+        assert_close(&V3 {x: 0.0, y: 0.0, z: 1.0}, &hit.normal);
+
+        let bbox = shape.bounding_box(&(0.0..1.0)).unwrap();
+        assert_close(&V3 {x: -1.0, y: -1.0, z: -5.5}, &bbox.min);
+        assert_close(&V3 {x: 1.0, y: 1.0, z: -4.5}, &bbox.max);
+    }
+}
@@ -73,6 +73,29 @@ impl_op_ex!(/ |a: &V3, b: &f32| -> V3 {
     }
 });
 
+/// decodes an sRGB encoded component (in 0..1) to linear
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// the sRGB transfer function, encodes a linear component in 0..1
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// the Rec. 709 weights, which add up to 1
+pub fn luminance(c: &Color) -> f32 {
+    0.2126*c.r + 0.7152*c.g + 0.0722*c.b
+}
+
 // https://www.reddit.com/r/rust/comments/29kia3/no_ord_for_f32/cilrzik/
 pub fn f32_cmp(a: f32, b: f32) -> cmp::Ordering {
     a.partial_cmp(&b).unwrap_or(cmp::Ordering::Equal)
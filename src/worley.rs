@@ -0,0 +1,193 @@
+// cellular noise (Steven Worley, 1996): distances to points
+// scattered one per unit cell
+
+use crate::{v3color::*, texture::*, rng::*};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DistanceMetric {
+    Euclidean,
+    /// the sum of the differences along the axes: diamond shaped cells
+    Manhattan,
+    /// the largest difference along an axis: square cells
+    Chebyshev
+}
+
+impl DistanceMetric {
+    pub fn from_name(name: &str) -> Option<DistanceMetric> {
+        match name {
+            "euclidean" => Some(DistanceMetric::Euclidean),
+            "manhattan" => Some(DistanceMetric::Manhattan),
+            "chebyshev" => Some(DistanceMetric::Chebyshev),
+            _ => None
+        }
+    }
+
+    fn length(self, d: &V3) -> f32 {
+        match self {
+            DistanceMetric::Euclidean => d.length(),
+            DistanceMetric::Manhattan => d.x.abs() + d.y.abs() + d.z.abs(),
+            DistanceMetric::Chebyshev => d.x.abs().max(d.y.abs()).max(d.z.abs())
+        }
+    }
+
+    /// the farthest the second closest point can be, in cells: the point
+    /// of the cell itself and the one of the neighbor across the closest
+    /// face are always within this distance
+    pub fn max_distance(self) -> f32 {
+        match self {
+            DistanceMetric::Euclidean => 3f32.sqrt(),
+            DistanceMetric::Manhattan => 3.0,
+            DistanceMetric::Chebyshev => 1.5
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WorleyFeature {
+    /// the distance to the closest point: round spots
+    F1,
+    /// to the second closest one
+    F2,
+    /// 0 on the borders between cells: cracks
+    F2MinusF1
+}
+
+impl WorleyFeature {
+    pub fn from_name(name: &str) -> Option<WorleyFeature> {
+        match name {
+            "f1" => Some(WorleyFeature::F1),
+            "f2" => Some(WorleyFeature::F2),
+            "f2-f1" => Some(WorleyFeature::F2MinusF1),
+            _ => None
+        }
+    }
+}
+
+/// the points are hashed from the cell coordinates and
+/// the seed, so they don't take any memory
+pub struct Worley {
+    pub seed: u64
+}
+
+impl Worley {
+    /// where the point of a cell is, relative to its corner
+    fn feature_offset(&self, x: i32, y: i32, z: i32) -> V3 {
+        let h = [x, y, z].iter().fold(mix64(self.seed), |h, &c| mix64(h ^ c as u32 as u64));
+        // 21 bits for each coordinate in the cell
+        let coordinate = |shift: u32| ((h >> shift) & 0x1f_ffff) as f32 / (1 << 21) as f32;
+        V3 { x: coordinate(0), y: coordinate(21), z: coordinate(42) }
+    }
+
+    /// the distances to the closest and second closest points, F1 and F2
+    pub fn distances(&self, p: &V3, metric: DistanceMetric) -> (f32, f32) {
+        let corner = V3 { x: p.x.floor(), y: p.y.floor(), z: p.z.floor() };
+        // relative to the cell, which stays precise far from the origin
+        let local = *p - corner;
+        // the cells only feed the hash, wrapping around doesn't matter
+        let (x, y, z) = (corner.x as i32, corner.y as i32, corner.z as i32);
+        // how far from p the closest side of a cell is along an axis
+        let gap = |d: i32, l: f32| (d as f32 - l).max(l - (d + 1) as f32).max(0.0);
+        let (mut f1, mut f2) = (f32::INFINITY, f32::INFINITY);
+        // a cell n cells away is over n - 1 away, the rings
+        // of cells around p's go on until that's too far
+        let rings = metric.max_distance().ceil() as i32;
+        for ring in 0..=rings {
+            for dx in -ring..=ring {
+                for dy in -ring..=ring {
+                    for dz in -ring..=ring {
+                        if dx.abs().max(dy.abs()).max(dz.abs()) < ring {
+                            continue;
+                        }
+                        let closest_side = V3 { x: gap(dx, local.x), y: gap(dy, local.y), z: gap(dz, local.z) };
+                        if metric.length(&closest_side) >= f2 {
+                            continue;
+                        }
+                        let offset = self.feature_offset(x.wrapping_add(dx), y.wrapping_add(dy), z.wrapping_add(dz));
+                        let neighbor = V3 { x: dx as f32, y: dy as f32, z: dz as f32 };
+                        let d = metric.length(&(neighbor + offset - local));
+                        if d < f1 {
+                            f2 = f1;
+                            f1 = d;
+                        } else if d < f2 {
+                            f2 = d;
+                        }
+                    }
+                }
+            }
+        }
+        (f1, f2)
+    }
+}
+
+/// gray levels from cellular noise: the distances in cells, over the
+/// farthest the second closest point can be, so that they stay in [0, 1]
+pub struct WorleyTexture {
+    pub worley: Worley,
+    /// cells per unit of distance
+    pub frequency: f32,
+    pub feature: WorleyFeature,
+    pub metric: DistanceMetric
+}
+
+impl Texture for WorleyTexture {
+    fn value(&self, _u: f32, _v: f32, p: &V3) -> Color {
+        let (f1, f2) = self.worley.distances(&(self.frequency * p), self.metric);
+        let distance = match self.feature {
+            WorleyFeature::F1 => f1,
+            WorleyFeature::F2 => f2,
+            WorleyFeature::F2MinusF1 => f2 - f1
+        };
+        gray(distance / self.metric.max_distance())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_worley() {
+        let worley = Worley { seed: 7 };
+        let metrics = [DistanceMetric::Chebyshev, DistanceMetric::Euclidean, DistanceMetric::Manhattan];
+        let mut rng = Pcg32::new(0, 0);
+        for _ in 0..1000 {
+            let p = V3 { x: 10.0 * rng.gen::<f32>() - 5.0, y: 0.5, z: -3.25 };
+            let distances: Vec<(f32, f32)> = metrics.iter().map(|&m| worley.distances(&p, m)).collect();
+            for (&(f1, f2), &metric) in distances.iter().zip(&metrics) {
+                assert!(0.0 <= f1 && f1 <= f2 && f2 <= metric.max_distance(), "{:?}", distances);
+            }
+            // the same points, measured with growing metrics
+            assert!(distances[0].0 <= distances[1].0 && distances[1].0 <= distances[2].0);
+        }
+        // the same as going through all the points around
+        for _ in 0..200 {
+            let p = V3 { x: 8.0 * rng.gen::<f32>() - 4.0, y: 3.0 * rng.gen::<f32>(), z: rng.gen::<f32>() };
+            for &metric in &metrics {
+                let mut all = Vec::new();
+                for x in -8..8 {
+                    for y in -4..8 {
+                        for z in -4..5 {
+                            let point = V3 { x: x as f32, y: y as f32, z: z as f32 } + worley.feature_offset(x, y, z);
+                            all.push(metric.length(&(point - p)));
+                        }
+                    }
+                }
+                all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let (f1, f2) = worley.distances(&p, metric);
+                assert!((f1 - all[0]).abs() < 1e-5 && (f2 - all[1]).abs() < 1e-5,
+                        "{:?} at {:?}: {} {}, {:?}", metric, p, f1, f2, &all[..2]);
+            }
+        }
+        let point = V3 { x: -2.0, y: 0.0, z: 3.0 } + worley.feature_offset(-2, 0, 3);
+        assert!(worley.distances(&point, DistanceMetric::Euclidean).0 < 1e-6);
+        // the same seed gives the same points, another one doesn't
+        assert_eq!(worley.feature_offset(-2, 0, 3), Worley { seed: 7 }.feature_offset(-2, 0, 3));
+        assert_ne!(worley.feature_offset(-2, 0, 3), Worley { seed: 8 }.feature_offset(-2, 0, 3));
+        // far away, where the cells can't be counted in an i32
+        for &x in &[3e9, -3e9, 1e10 + 0.5, f32::MAX, f32::MIN] {
+            let (f1, f2) = worley.distances(&V3 { x, y: 0.5, z: 0.5 }, DistanceMetric::Euclidean);
+            assert!(0.0 <= f1 && f1 <= f2 && f2 < 3.0, "{}: {} {}", x, f1, f2);
+        }
+    }
+}